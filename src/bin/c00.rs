@@ -1,7 +1,7 @@
 #![allow(deprecated)]
 
 use clap::Parser;
-use deckompiler::c00::{C00Bin, C00Type};
+use deckompiler::c00::{C00Bin, C00Type, NameResolver};
 use std::{
     fs::{self, File},
     io::{Result as IOResult, Write},
@@ -18,19 +18,36 @@ fn main() {
 fn run() -> IOResult<()> {
     let cli = Cli::parse();
     let mut f = File::open(cli.c00)?;
-    let c00 = C00Bin::from_file(&mut f, C00Type::RHMPatch, cli.old)?;
+    let c00 = match cli.vanilla {
+        Some(vanilla) => {
+            let mut vanilla = File::open(vanilla)?;
+            C00Bin::from_file_with_vanilla(&mut f, Some(&mut vanilla), C00Type::RHMPatch, cli.old)?
+        }
+        None => C00Bin::from_file(&mut f, C00Type::RHMPatch, cli.old)?,
+    };
+    for change in &c00.changes {
+        println!("{}", change);
+    }
+    let mut names = NameResolver::new();
+    for map in &cli.names {
+        names.load_map(&fs::read_to_string(map)?)?;
+    }
     fs::create_dir_all(&cli.out)?;
     for tfbin in c00.tickflows {
         let mut out = cli.out.clone();
-        out.push(PathBuf::from(format!("{}.bin", tfbin.name())));
+        out.push(PathBuf::from(format!("{}.bin", tfbin.name_with(&names))));
         let mut bin = File::create(out)?;
         tfbin.to_file(&mut bin)?;
     }
     for tempo in c00.tempos {
         let mut out = cli.out.clone();
-        out.push(PathBuf::from(format!("{}.tempo", tempo.name())));
-        let mut tfile = File::create(out)?;
-        tfile.write_all(tempo.to_tickompiler_file().as_bytes())?;
+        out.push(PathBuf::from(format!("{}.tempo", tempo.name_with(&names))));
+        let mut tfile = File::create(&out)?;
+        tfile.write_all(tempo.to_tickompiler_file(cli.extended).as_bytes())?;
+        if cli.midi {
+            let mut midi = File::create(out.with_extension("mid"))?;
+            tempo.to_midi(&mut midi)?;
+        }
     }
     Ok(())
 }
@@ -46,4 +63,18 @@ struct Cli {
     /// Enable this if your C00.bin predates the Aug 2017 gate patch
     #[clap(short = 'o', long = "old-c00")]
     old: bool,
+    /// A vanilla C00.bin to compare against, so that edits made in place are extracted too
+    #[clap(short = 'v', long = "vanilla")]
+    vanilla: Option<PathBuf>,
+    /// Name maps for custom game indices and tempo IDs, with lines like `game 0x1A0 myGame`
+    /// or `tempo 0x02000005 myTempo`
+    #[clap(short = 'n', long = "names")]
+    names: Vec<PathBuf>,
+    /// Also export every tempo as a MIDI file with its tempo map
+    #[clap(short = 'm', long = "midi")]
+    midi: bool,
+    /// Write .tempo files with `@time` for times no BPM gives back and whether they're
+    /// streamed, which Tickompiler can't read
+    #[clap(short = 'e', long = "extended")]
+    extended: bool,
 }
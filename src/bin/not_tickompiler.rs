@@ -1,6 +1,10 @@
 #![allow(deprecated)]
 
 use clap::Parser;
+use deckompiler::{
+    c00::C00Type,
+    compiler::{preprocess::Preprocessor, symbols::C00Placement},
+};
 use std::{fs, path::PathBuf};
 use tickflow_parse::{Error, Result};
 
@@ -17,10 +21,26 @@ fn main() {
 fn run() -> Result<()> {
     let cli = Cli::parse();
     cli.out.parent().map(fs::create_dir_all).unwrap_or(Ok(()))?;
-    deckompiler::compiler::compile_file(
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.include_dirs = cli.include;
+    preprocessor.library = cli.lib;
+    deckompiler::compiler::compile_file_with_preprocessor(
         cli.in_,
         cli.out,
-        deckompiler::compiler::CompiledFileType::Tickompiler,
+        if cli.object {
+            deckompiler::compiler::CompiledFileType::Object
+        } else {
+            deckompiler::compiler::CompiledFileType::Tickompiler
+        },
+        preprocessor,
+        &deckompiler::compiler::ExtraOutputs {
+            listing: cli.listing,
+            symbols: cli.symbols,
+            c00: cli.c00_position.map(|position| C00Placement {
+                c00_type: C00Type::RHMPatch,
+                position,
+            }),
+        },
     )?;
     Ok(())
 }
@@ -38,4 +58,28 @@ struct Cli {
     in_: PathBuf,
     /// Location for the file to be compiled to
     out: PathBuf,
+    /// Folders to look for included files in, after the including file's own folder
+    #[clap(short = 'I', long)]
+    include: Vec<PathBuf>,
+    /// The project's library folder, looked in for included files after all the others
+    #[clap(short = 'L', long)]
+    lib: Option<PathBuf>,
+    /// Also write a listing of every command's offset, encoded words and source line here
+    #[clap(long)]
+    listing: Option<PathBuf>,
+    /// Compile to an object module, whose labels from other modules get resolved when it's
+    /// linked with `deckompiler link`
+    #[clap(short = 'c', long)]
+    object: bool,
+    /// Also write a CSV file with the offset and source line of every label here
+    #[clap(long)]
+    symbols: Option<PathBuf>,
+    /// Position in C00.bin that the compiled tickflow gets packed at, in hex, to add each label's
+    /// absolute address to the symbol file
+    #[clap(long, value_parser = parse_hex)]
+    c00_position: Option<u32>,
+}
+
+fn parse_hex(num: &str) -> std::result::Result<u32, String> {
+    u32::from_str_radix(num.trim_start_matches("0x"), 16).map_err(|e| e.to_string())
 }
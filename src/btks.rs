@@ -1,4 +1,7 @@
-use crate::common::Tempo;
+use crate::{
+    common::Tempo,
+    tickflow::{Statement, StringPool, Tickflow, ANN_POINTER, ANN_STRING, ANN_USTRING},
+};
 use bytestream::{ByteOrder, StreamReader, StreamWriter};
 use std::{
     fs::File,
@@ -86,79 +89,61 @@ impl BTKS {
         tempos: Vec<PathBuf>,
         is_gprac: bool,
     ) -> io::Result<Self> {
-        f.seek(SeekFrom::Start(4))?;
+        f.seek(SeekFrom::Start(0))?;
+        let bin = Tickflow::from_bin(&mut f.take(file_size))?;
+        let start = if is_gprac { bin.assets } else { bin.start };
 
-        let start: u32;
-        if !is_gprac {
-            start = u32::read_from(f, ByteOrder::LittleEndian)?;
-            u32::read_from(f, ByteOrder::LittleEndian)?;
-        } else {
-            u32::read_from(f, ByteOrder::LittleEndian)?;
-            start = u32::read_from(f, ByteOrder::LittleEndian)?;
-        }
+        // strings and tickflow pointers are both kept in PTRO, but strings go in their own
+        // section with only one copy of each
         let mut tickflow = vec![];
-        // .bin tickflow loop or whatever
-        // copied from tickompiler, modified to export to btks
         let mut pointers = vec![];
-        loop {
-            let mut cmd = u32::read_from(f, ByteOrder::LittleEndian)?;
-            if cmd == 0xFFFFFFFE {
-                // 0xFFFFFFFE (-2) indicates start of string data
-                break;
-            }
-            //TODO: manage bytes argument annotation
-            let mut str_args = vec![]; //strings and tickflow pointers have to be stored separately
-            let mut ptr_args = vec![]; //because they're managed differently in btks
-            if cmd == 0xFFFFFFFF {
-                // 0xFFFFFFFF (-1) indicates an 'args' section
-                let amount = u32::read_from(f, ByteOrder::LittleEndian)?;
-                for _ in 0..amount {
-                    let ann = u32::read_from(f, ByteOrder::LittleEndian)?;
-                    let anncode = ann & 0xFF;
-                    let ann_arg = (ann & 0xFFFFFF) >> 8;
-                    match anncode {
-                        0 => ptr_args.push(ann_arg),
-                        1 | 2 => str_args.push(ann_arg),
-                        3 => todo!(), // how even do i do this....
-                        _ => unreachable!(),
-                    }
-                }
-                cmd = u32::read_from(f, ByteOrder::LittleEndian)?;
-            }
-            tickflow.extend(cmd.to_le_bytes());
-            let arg_count = (cmd >> 10) & 0xF;
-            for i in 0..arg_count {
-                let arg = u32::read_from(f, ByteOrder::LittleEndian)?;
-                if str_args.contains(&i) {
-                    pointers.push(Pointer {
-                        offset: tickflow.len() as u32,
-                        ptype: PointerType::String,
-                    })
-                } else if ptr_args.contains(&i) {
-                    pointers.push(Pointer {
-                        offset: tickflow.len() as u32,
-                        ptype: PointerType::Tickflow,
-                    })
+        let mut pool = StringPool::new();
+        for statement in &bin.statements {
+            let (cmd, arg0, args, anns) = match statement {
+                Statement::Data { data, .. } => {
+                    // raw data has no pointers in it, so it's copied as is, padding included
+                    tickflow.extend(data);
+                    tickflow.extend(vec![0; (4 - data.len() % 4) % 4]);
+                    continue;
                 }
+                Statement::Command {
+                    cmd,
+                    arg0,
+                    args,
+                    anns,
+                    ..
+                } => (*cmd, *arg0, args, anns),
+            };
+            let op_int = cmd as u32 + ((args.len() as u32) << 10) + (arg0 << 14);
+            tickflow.extend(op_int.to_le_bytes());
+            for (i, arg) in args.iter().enumerate() {
+                let ann = anns.iter().find(|c| c.0 == i as u32).map(|c| c.1);
+                let arg = match ann {
+                    Some(ANN_POINTER) => {
+                        pointers.push(Pointer {
+                            offset: tickflow.len() as u32,
+                            ptype: PointerType::Tickflow,
+                        });
+                        *arg
+                    }
+                    Some(c @ (ANN_STRING | ANN_USTRING)) => {
+                        let is_unicode = c == ANN_USTRING;
+                        let chars = bin.string_chars_at(*arg, is_unicode).ok_or(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "string pointer points outside of the string data",
+                        ))?;
+                        pointers.push(Pointer {
+                            offset: tickflow.len() as u32,
+                            ptype: PointerType::String,
+                        });
+                        pool.add_encoded(chars, is_unicode)
+                    }
+                    _ => *arg,
+                };
                 tickflow.extend(arg.to_le_bytes());
             }
         }
-        let mut strings = vec![0; (file_size - f.stream_position()?) as usize];
-        f.read_exact(&mut strings)?;
-        let stringpos = tickflow.len();
-        //fix string pointers - stringpos, etc
-        for ptr in &pointers {
-            if let PointerType::String = ptr.ptype {
-                let mut ptr_bytes: [u8; 4] = [0; 4];
-                for i in 0..4 {
-                    ptr_bytes[i] = tickflow[ptr.offset as usize + i];
-                }
-                let str_ptr = (u32::from_le_bytes(ptr_bytes) - stringpos as u32).to_le_bytes();
-                for i in 0..4 {
-                    tickflow[ptr.offset as usize + i] = str_ptr[i];
-                }
-            }
-        }
+        let strings = pool.into_data();
 
         let section_flow = FlowSection {
             start_offset: start,
@@ -181,11 +166,11 @@ impl BTKS {
                 let mut f = File::open(path)?;
                 let mut tempo_data = String::new();
                 f.read_to_string(&mut tempo_data)?;
-                out.push(match Tempo::from_tickompiler_file(tempo_data) {
-                    Some(c) => c,
-                    None => Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Couldn't open tempo file '{}'", path.to_str().unwrap()),
+                out.push(match Tempo::from_tickompiler_file(&tempo_data) {
+                    Ok(c) => c,
+                    Err(e) => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Couldn't parse tempo file '{}': {}", path.display(), e),
                     ))?,
                 })
             }
@@ -1,16 +1,25 @@
-use crate::common::{Tempo, TempoVal};
+use crate::common::{LoopFlags, Tempo, TempoVal};
 use bytestream::{ByteOrder, StreamReader, StreamWriter};
-use std::io::{Read, Result as IOResult, Seek, SeekFrom, Write};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    io::{Cursor, Read, Result as IOResult, Seek, SeekFrom, Write},
+};
 
 pub mod constants;
+pub mod names;
 pub mod operations;
 
+pub use names::{NameError, NameResolver};
+
 #[derive(Debug)]
 pub struct C00Bin {
     pub c00_type: C00Type,
     pub base_patch: Patch, //TODO: IPS??? custom format??? to be decided
     pub tickflows: Vec<TickompilerBinary>,
     pub tempos: Vec<Tempo>,
+    pub changes: Vec<C00Change>,
 }
 
 //stub
@@ -59,66 +68,267 @@ pub enum Pointer {
     Tickflow { offset: u32, points_to: u32 },
 }
 
-impl C00Bin {
-    pub fn base_offset(&self) -> u32 {
-        self.c00_type.base_offset()
+/// A difference found between a C00.bin and the vanilla C00.bin it's compared against
+///
+/// `SharedTickflow` is for tickflow that more than one game calls, which counts as an edit to
+/// all of them.
+#[derive(Debug, Clone)]
+pub enum C00Change {
+    GameTable { index: u32 },
+    GameTickflow { index: u32, offset: u32 },
+    SharedTickflow { indices: Vec<u32>, offset: u32 },
+    TempoTable { slot: u32 },
+    TempoData { slot: u32, offset: u32 },
+}
+
+impl Display for C00Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::GameTable { index } => write!(f, "game table entry {:#X} changed", index),
+            Self::GameTickflow { index, offset } => write!(
+                f,
+                "tickflow for game {:#X} changed at 0x{:08X}",
+                index, offset
+            ),
+            Self::SharedTickflow { indices, offset } => write!(
+                f,
+                "tickflow shared by games {} changed at 0x{:08X}",
+                indices
+                    .iter()
+                    .map(|c| format!("{:#X}", c))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                offset
+            ),
+            Self::TempoTable { slot } => write!(f, "tempo table entry {:#X} changed", slot),
+            Self::TempoData { slot, offset } => write!(
+                f,
+                "tempo data for entry {:#X} changed at 0x{:08X}",
+                slot, offset
+            ),
+        }
     }
+}
 
-    pub fn from_file<F: Read + Seek>(file: &mut F, c00_type: C00Type, old: bool) -> IOResult<Self> {
-        let mut edited_games = vec![];
-        let mut edited_tempos = vec![];
+/// The game and tempo tables in base.bin, along with the raw bytes of each entry
+struct BaseTables {
+    games: Vec<(TickompilerBinary, Vec<u8>)>,
+    tempos: Vec<(TempoTable, Vec<u8>)>,
+}
 
-        //TODO: detect base.bin patches
+impl BaseTables {
+    const GAME_ENTRY_SIZE: usize = 0x34;
+    const GATE_ENTRY_SIZE: usize = 0x24;
+    const TEMPO_ENTRY_SIZE: usize = 0x10;
 
-        // Step 1 - Go through the base.bin tables and try to find the positions
-        //    (if they're greater than 0x550000, then it's modded)
+    fn read<F: Read + Seek>(file: &mut F, old: bool) -> IOResult<Self> {
+        let mut games = vec![];
+        let mut tempos = vec![];
 
         //game table
         for i in 0..0x68 {
-            file.seek(SeekFrom::Current(4))?;
-            let start = u32::read_from(file, ByteOrder::LittleEndian)?;
-            let assets = u32::read_from(file, ByteOrder::LittleEndian)?;
-            if start >= 0x550000 {
-                edited_games.push(TickompilerBinary {
-                    index: i,
-                    start,
-                    assets,
-                    data: vec![],
-                });
-            }
-            file.seek(SeekFrom::Current(0x28))?;
+            games.push(Self::read_game(file, i, Self::GAME_ENTRY_SIZE)?);
         }
         file.seek(SeekFrom::Current(0x38))?; // This Shit Should Not Be In Base Dot Bin
 
         //tempo table
         for _ in 0..0x1E0 {
-            let id1 = u32::read_from(file, ByteOrder::LittleEndian)?; // padding
-            let id2 = u32::read_from(file, ByteOrder::LittleEndian)?;
-            let unk = u32::read_from(file, ByteOrder::LittleEndian)?;
-            let pos = u32::read_from(file, ByteOrder::LittleEndian)?;
-            if pos >= 0x550000 {
-                edited_tempos.push(TempoTable { id1, unk, pos, id2 });
-            }
+            let mut raw = vec![0; Self::TEMPO_ENTRY_SIZE];
+            file.read_exact(&mut raw)?;
+            let word = |i: usize| u32::from_le_bytes(raw[i * 4..i * 4 + 4].try_into().unwrap());
+            let tempo = TempoTable {
+                id1: word(0), // sequenced audio ID
+                id2: word(1), // streamed audio ID
+                unk: word(2),
+                pos: word(3),
+            };
+            tempos.push((tempo, raw));
         }
 
         //gate table
         if !old {
             for i in 0x100..0x110 {
-                file.seek(SeekFrom::Current(4))?;
-                let start = u32::read_from(file, ByteOrder::LittleEndian)?;
-                let assets = u32::read_from(file, ByteOrder::LittleEndian)?;
-                if start >= 0x550000 {
-                    edited_games.push(TickompilerBinary {
-                        index: i,
-                        start,
-                        assets,
-                        data: vec![],
-                    });
+                games.push(Self::read_game(file, i, Self::GATE_ENTRY_SIZE)?);
+            }
+        }
+
+        Ok(Self { games, tempos })
+    }
+
+    fn read_game<F: Read>(
+        file: &mut F,
+        index: u32,
+        size: usize,
+    ) -> IOResult<(TickompilerBinary, Vec<u8>)> {
+        let mut raw = vec![0; size];
+        file.read_exact(&mut raw)?;
+        let word = |i: usize| u32::from_le_bytes(raw[i * 4..i * 4 + 4].try_into().unwrap());
+        let game = TickompilerBinary {
+            index,
+            start: word(1),
+            assets: word(2),
+            data: vec![],
+        };
+        Ok((game, raw))
+    }
+}
+
+impl C00Bin {
+    pub fn base_offset(&self) -> u32 {
+        self.c00_type.base_offset()
+    }
+
+    pub fn from_file<F: Read + Seek>(file: &mut F, c00_type: C00Type, old: bool) -> IOResult<Self> {
+        Self::from_file_with_vanilla(file, None::<&mut F>, c00_type, old)
+    }
+
+    /// Extracts a C00.bin, comparing it against a vanilla C00.bin if one is given
+    ///
+    /// Without a vanilla C00.bin, anything placed at or after 0x550000 is assumed to be modded.
+    /// With one, every table entry and tickflow region that differs from it counts as modded,
+    /// so that edits made in place get extracted too.
+    pub fn from_file_with_vanilla<F: Read + Seek, V: Read + Seek>(
+        file: &mut F,
+        vanilla: Option<&mut V>,
+        c00_type: C00Type,
+        old: bool,
+    ) -> IOResult<Self> {
+        let mut edited_games = vec![];
+        let mut edited_tempos = vec![];
+        let mut changes = vec![];
+
+        // everything below is lots of small reads all over the files, so they're read into
+        // memory once instead
+        let file = &mut Cursor::new(read_all(file)?);
+        let mut vanilla = match vanilla {
+            Some(c) => Some(Cursor::new(read_all(c)?)),
+            None => None,
+        };
+
+        //TODO: detect base.bin patches
+
+        // Step 1 - Go through the base.bin tables and try to find the positions
+        //    (if there's no vanilla C00 to compare with and they're greater than 0x550000,
+        //    then it's modded)
+        let tables = BaseTables::read(file, old)?;
+
+        match vanilla.as_mut() {
+            None => {
+                for (game, _) in tables.games {
+                    if game.start >= 0x550000 {
+                        edited_games.push(game);
+                    }
+                }
+                for (tempo, _) in tables.tempos {
+                    if tempo.pos >= 0x550000 {
+                        edited_tempos.push(tempo);
+                    }
+                }
+            }
+            Some(vanilla) => {
+                let vanilla_tables = BaseTables::read(vanilla, old)?;
+                let mut game_regions = vec![];
+                let mut callers: HashMap<(u32, u32), Vec<u32>> = HashMap::new();
+                for (game, _) in &tables.games {
+                    let regions = tickflow_regions(&c00_type, file, &[game.start, game.assets])?;
+                    for region in &regions {
+                        callers.entry(*region).or_default().push(game.index);
+                    }
+                    game_regions.push(regions);
+                }
+                let mut shared_compared = HashSet::new();
+                // a change to shared tickflow can come after some of the games that call it, so
+                // which games are edited is only known once they've all been compared
+                let mut edited = HashSet::new();
+                let mut games = vec![];
+                for (((game, raw), (_, vanilla_raw)), regions) in tables
+                    .games
+                    .into_iter()
+                    .zip(vanilla_tables.games)
+                    .zip(game_regions)
+                {
+                    if raw != vanilla_raw {
+                        changes.push(C00Change::GameTable { index: game.index });
+                        edited.insert(game.index);
+                    }
+                    for region in regions {
+                        let indices = &callers[&region];
+                        let is_shared = indices.len() > 1;
+                        if is_shared && !shared_compared.insert(region) {
+                            continue;
+                        }
+                        let Some(offset) =
+                            first_difference(&c00_type, file, vanilla, region.0, region.1)?
+                        else {
+                            continue;
+                        };
+                        if is_shared {
+                            changes.push(C00Change::SharedTickflow {
+                                indices: indices.clone(),
+                                offset,
+                            });
+                            edited.extend(indices);
+                        } else {
+                            changes.push(C00Change::GameTickflow {
+                                index: game.index,
+                                offset,
+                            });
+                            edited.insert(game.index);
+                        }
+                    }
+                    games.push(game);
+                }
+                edited_games.extend(games.into_iter().filter(|c| edited.contains(&c.index)));
+                for (slot, ((tempo, raw), (_, vanilla_raw))) in tables
+                    .tempos
+                    .into_iter()
+                    .zip(vanilla_tables.tempos)
+                    .enumerate()
+                {
+                    let slot = slot as u32;
+                    let mut edited = false;
+                    if raw != vanilla_raw {
+                        changes.push(C00Change::TempoTable { slot });
+                        edited = true;
+                    }
+                    if tempo.pos >= c00_type.base_offset() {
+                        let len = read_tempo_vals(&c00_type, file, tempo.pos)?.len() as u32 * 12;
+                        if let Some(offset) =
+                            first_difference(&c00_type, file, vanilla, tempo.pos, tempo.pos + len)?
+                        {
+                            changes.push(C00Change::TempoData { slot, offset });
+                            edited = true;
+                        }
+                    }
+                    if edited {
+                        edited_tempos.push(tempo);
+                    }
                 }
-                file.seek(SeekFrom::Current(0x18))?;
             }
         }
 
+        // Entries pointing outside of the C00.bin can't be extracted from it
+        edited_games.retain(|game| {
+            let in_c00 = game.start >= c00_type.base_offset();
+            if !in_c00 {
+                println!(
+                    "Warning: game {:#X} points to tickflow outside the C00.bin at 0x{:08X}, skipping",
+                    game.index, game.start
+                );
+            }
+            in_c00
+        });
+        edited_tempos.retain(|tempo| {
+            let in_c00 = tempo.pos >= c00_type.base_offset();
+            if !in_c00 {
+                println!(
+                    "Warning: tempo {:08X}/{:08X} points to data outside the C00.bin at 0x{:08X}, skipping",
+                    tempo.id1, tempo.id2, tempo.pos
+                );
+            }
+            in_c00
+        });
+
         // Step 2 - Read and extract tickflow .bin-s
         for game in &mut edited_games {
             let mut func_order = vec![];
@@ -177,36 +387,21 @@ impl C00Bin {
         // Step 3 - Read and extract .tempo-s
         let mut tempos = vec![];
         for tempo in &edited_tempos {
-            // Note: for all tempos, one ID is 0xFFFFFFFF
-            let mut tempo_vals = vec![];
-            file.seek(SeekFrom::Start(
-                tempo.pos as u64 - c00_type.base_offset() as u64,
-            ))?;
-            loop {
-                let beats_bytes = u32::read_from(file, ByteOrder::LittleEndian)?;
-                let beats = f32::from_bits(beats_bytes);
-                let time = u32::read_from(file, ByteOrder::LittleEndian)?;
-                let loop_val = u32::read_from(file, ByteOrder::LittleEndian)?;
-                tempo_vals.push(TempoVal {
-                    beats,
-                    time,
-                    loop_val,
-                });
-                //pretty sure this is the observable behavior
-                if loop_val & 0x8001 != 0 {
-                    break;
-                }
-            }
+            // Note: for all tempos, one ID is 0xFFFFFFFF, and which one is set says whether
+            // the tempo is for sequenced or streamed audio
+            let tempo_vals = read_tempo_vals(&c00_type, file, tempo.pos)?;
             if tempo.id1 != 0xFFFFFFFF {
                 tempos.push(Tempo {
                     id: tempo.id1,
                     data: tempo_vals.clone(),
+                    streamed: Some(false),
                 })
             }
             if tempo.id2 != 0xFFFFFFFF {
                 tempos.push(Tempo {
                     id: tempo.id2,
                     data: tempo_vals,
+                    streamed: Some(true),
                 })
             }
         }
@@ -218,10 +413,119 @@ impl C00Bin {
             base_patch: Patch, //TODO
             tickflows: edited_games,
             tempos,
+            changes,
         })
     }
 }
 
+/// Reads the values of the tempo at the given position, up until its last value
+pub fn read_tempo_vals<F: Read + Seek>(
+    c00_type: &C00Type,
+    file: &mut F,
+    pos: u32,
+) -> IOResult<Vec<TempoVal>> {
+    let mut tempo_vals = vec![];
+    file.seek(SeekFrom::Start(pos as u64 - c00_type.base_offset() as u64))?;
+    loop {
+        let beats_bytes = u32::read_from(file, ByteOrder::LittleEndian)?;
+        let beats = f32::from_bits(beats_bytes);
+        let time = u32::read_from(file, ByteOrder::LittleEndian)?;
+        let loop_val = LoopFlags::from_bits(u32::read_from(file, ByteOrder::LittleEndian)?);
+        tempo_vals.push(TempoVal {
+            beats,
+            time,
+            loop_val,
+        });
+        //pretty sure this is the observable behavior
+        if loop_val.is_terminator() {
+            break;
+        }
+    }
+    Ok(tempo_vals)
+}
+
+fn read_all<F: Read + Seek>(file: &mut F) -> IOResult<Vec<u8>> {
+    let mut data = vec![];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Finds the regions (start and end positions) of all tickflow inside the C00.bin that's
+/// reachable from the given positions
+///
+/// Follows the same rules as [`extract_tickflow`] to find where each function ends
+pub fn tickflow_regions<F: Read + Seek>(
+    c00_type: &C00Type,
+    file: &mut F,
+    positions: &[u32],
+) -> IOResult<Vec<(u32, u32)>> {
+    let base_offset = c00_type.base_offset();
+    let mut queue = vec![];
+    for pos in positions {
+        if *pos >= base_offset && !queue.contains(pos) {
+            queue.push(*pos);
+        }
+    }
+    let mut regions = vec![];
+    let mut i = 0;
+    while i < queue.len() {
+        file.seek(SeekFrom::Start((queue[i] - base_offset) as u64))?;
+        let mut depth = 0;
+        loop {
+            let op_int = u32::read_from(file, ByteOrder::LittleEndian)?;
+            let arg_count = ((op_int & 0x3C00) >> 10) as u8;
+            let mut args = vec![];
+            for _ in 0..arg_count {
+                args.push(u32::read_from(file, ByteOrder::LittleEndian)?);
+            }
+            if let Some(c) = operations::is_call_op(op_int) {
+                let pointer_pos = args[c.args[0] as usize];
+                if pointer_pos >= base_offset && !queue.contains(&pointer_pos) {
+                    queue.push(pointer_pos);
+                }
+            } else if operations::is_depth_op(op_int).is_some() {
+                depth += 1;
+            } else if operations::is_undepth_op(op_int).is_some() {
+                if depth > 0 {
+                    depth -= 1;
+                }
+            } else if operations::is_return_op(op_int).is_some() && depth <= 0 {
+                break;
+            }
+        }
+        regions.push((queue[i], file.stream_position()? as u32 + base_offset));
+        i += 1;
+    }
+    Ok(regions)
+}
+
+/// Compares a region of a C00.bin with the same region in another one, returning the
+/// position of the first byte that differs
+pub fn first_difference<F: Read + Seek, V: Read + Seek>(
+    c00_type: &C00Type,
+    file: &mut F,
+    other: &mut V,
+    start: u32,
+    end: u32,
+) -> IOResult<Option<u32>> {
+    let file_pos = (start - c00_type.base_offset()) as u64;
+    let mut data = vec![0; (end - start) as usize];
+    file.seek(SeekFrom::Start(file_pos))?;
+    file.read_exact(&mut data)?;
+
+    // the other file may be shorter, in which case whatever's past its end counts as different
+    let mut other_data = vec![];
+    other.seek(SeekFrom::Start(file_pos))?;
+    (&mut *other)
+        .take(data.len() as u64)
+        .read_to_end(&mut other_data)?;
+
+    Ok((0..data.len())
+        .find(|&i| other_data.get(i) != Some(&data[i]))
+        .map(|i| start + i as u32))
+}
+
 /// Equivalent to Tickompiler's firstPass
 pub fn extract_tickflow<F: Read + Seek>(
     c00_type: &C00Type,
@@ -391,27 +695,44 @@ impl TickompilerBinary {
         Ok(())
     }
 
-    pub fn name(&self) -> &str {
-        if self.index >= 0x100 {
-            constants::NAME_TICKFLOW_ENDLESS[self.index as usize - 0x100]
-        } else {
-            constants::NAME_TICKFLOW[self.index as usize]
-        }
+    pub fn name(&self) -> Cow<'static, str> {
+        names::builtin_game_name(self.index)
+    }
+
+    pub fn name_with<'a>(&self, names: &'a NameResolver) -> Cow<'a, str> {
+        names.game_name(self.index)
     }
 }
 
 impl Tempo {
-    pub fn to_tickompiler_file(&self) -> String {
-        let mut out = format!("{:X}\n", self.id);
+    /// Writes the tempo as a .tempo file
+    ///
+    /// Every BPM is written with as many digits as it takes to get the exact time back. Without
+    /// `extended`, only what Tickompiler can read is written, so a time that no BPM gives back
+    /// gets the closest BPM instead. With it, such times are written as `@time`, and the file
+    /// also says whether the tempo is streamed.
+    pub fn to_tickompiler_file(&self, extended: bool) -> String {
+        let mut out = match (extended, self.streamed) {
+            (true, Some(true)) => format!("{:X} streamed\n", self.id),
+            (true, Some(false)) => format!("{:X} sequenced\n", self.id),
+            _ => format!("{:X}\n", self.id),
+        };
         for val in &self.data {
-            let seconds = val.time as f64 / 32000.0;
-            let bpm = 60.0 * val.beats as f64 / seconds;
-            out += &format!("{:#.3} {:#.3} {}\n", bpm, val.beats as f64, val.loop_val);
+            let bpm = match (val.exact_bpm(), extended) {
+                (Some(c), _) => c.to_string(),
+                (None, true) => format!("@{}", val.time),
+                (None, false) => (val.bpm() as f32).to_string(),
+            };
+            out += &format!("{} {} {}\n", bpm, val.beats, val.loop_val.bits());
         }
         out
     }
 
-    pub fn name(&self) -> &str {
-        constants::NAME_TEMPO[self.id as usize - 0x1000000]
+    pub fn name(&self) -> Cow<'static, str> {
+        names::builtin_tempo_name(self.id)
+    }
+
+    pub fn name_with<'a>(&self, names: &'a NameResolver) -> Cow<'a, str> {
+        names.tempo_name(self.id)
     }
 }
@@ -0,0 +1,226 @@
+use super::constants::{NAME_TEMPO, NAME_TICKFLOW, NAME_TICKFLOW_ENDLESS};
+use std::{borrow::Cow, collections::HashMap, fmt, io};
+
+/// Resolves game indices and tempo IDs to readable names
+///
+/// Names given through [`NameResolver::load_map`] take priority over the built-in tables.
+/// IDs that aren't known by either get a generated name, such as `game_0x1A0` or
+/// `tempo_0x02000005`, so that resolving a name never fails.
+#[derive(Debug, Clone, Default)]
+pub struct NameResolver {
+    games: HashMap<u32, String>,
+    tempos: HashMap<u32, String>,
+}
+
+impl NameResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads a name map, overriding any names previously given for the same IDs
+    ///
+    /// Each line is either `game <index> <name>` or `tempo <id> <name>`, with the index/ID
+    /// in hexadecimal (the `0x` prefix is optional). Blank lines and lines starting with `//`
+    /// are ignored.
+    pub fn load_map(&mut self, map: &str) -> io::Result<()> {
+        for (i, line) in map.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            let error = |msg: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("name map line {}: {}", i + 1, msg),
+                )
+            };
+            let mut parts = line.split_whitespace();
+            let (Some(kind), Some(id), Some(name), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                Err(error("expected '<game/tempo> <id> <name>'"))?
+            };
+            let id = u32::from_str_radix(id.trim_start_matches("0x"), 16)
+                .map_err(|_| error("expected a hexadecimal ID"))?;
+            match kind {
+                "game" => self.games.insert(id, name.to_string()),
+                "tempo" => self.tempos.insert(id, name.to_string()),
+                _ => Err(error("expected 'game' or 'tempo'"))?,
+            };
+        }
+        Ok(())
+    }
+
+    pub fn game_name(&self, index: u32) -> Cow<'_, str> {
+        match self.games.get(&index) {
+            Some(c) => Cow::Borrowed(c),
+            None => builtin_game_name(index),
+        }
+    }
+
+    pub fn tempo_name(&self, id: u32) -> Cow<'_, str> {
+        match self.tempos.get(&id) {
+            Some(c) => Cow::Borrowed(c),
+            None => builtin_tempo_name(id),
+        }
+    }
+}
+
+pub fn builtin_game_name(index: u32) -> Cow<'static, str> {
+    let name = if index >= 0x100 {
+        NAME_TICKFLOW_ENDLESS.get(index as usize - 0x100)
+    } else {
+        NAME_TICKFLOW.get(index as usize)
+    };
+    match name {
+        Some(c) => Cow::Borrowed(c),
+        None => Cow::Owned(format!("game_0x{:X}", index)),
+    }
+}
+
+pub fn builtin_tempo_name(id: u32) -> Cow<'static, str> {
+    let name = id
+        .checked_sub(0x1000000)
+        .and_then(|c| NAME_TEMPO.get(c as usize));
+    match name {
+        Some(c) => Cow::Borrowed(c),
+        None => Cow::Owned(format!("tempo_0x{:08X}", id)),
+    }
+}
+
+/// A name that couldn't be resolved to a game index or tempo ID
+#[derive(Debug, Clone)]
+pub struct NameError {
+    /// What kind of name it was, such as `game` or `tempo`
+    pub kind: &'static str,
+    pub name: String,
+    /// Known names that are close to the given one
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown {} name '{}'", self.kind, self.name)?;
+        match self.suggestions.as_slice() {
+            [] => Ok(()),
+            [c] => write!(f, ", did you mean '{}'?", c),
+            c => write!(f, ", did you mean one of '{}'?", c.join("', '")),
+        }
+    }
+}
+
+impl From<NameError> for io::Error {
+    fn from(value: NameError) -> Self {
+        io::Error::new(io::ErrorKind::Other, value.to_string())
+    }
+}
+
+impl NameResolver {
+    /// Finds the game index with the given name, ignoring case if there's no exact match
+    pub fn game_index(&self, name: &str) -> Result<u32, NameError> {
+        let builtin = NAME_TICKFLOW
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i as u32, *c))
+            .chain(
+                NAME_TICKFLOW_ENDLESS
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| (i as u32 + 0x100, *c)),
+            );
+        let candidates = self
+            .games
+            .iter()
+            .map(|(id, c)| (*id, c.as_str()))
+            .chain(builtin);
+        find_name("game", name, "game_0x", candidates)
+    }
+
+    /// Finds the tempo ID with the given name, ignoring case if there's no exact match
+    ///
+    /// The built-in names are the game's own names for its sounds, such as `STRM_TITLE` or
+    /// `WSD_DAISUKI_RAP`, and not named after games, so tempos are written like
+    /// `tempo.STRM_TITLE` rather than `tempo.rvlRap_start`.
+    pub fn tempo_id(&self, name: &str) -> Result<u32, NameError> {
+        let builtin = NAME_TEMPO
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i as u32 + 0x1000000, *c));
+        let candidates = self
+            .tempos
+            .iter()
+            .map(|(id, c)| (*id, c.as_str()))
+            .chain(builtin);
+        find_name("tempo", name, "tempo_0x", candidates)
+    }
+}
+
+fn find_name<'a>(
+    kind: &'static str,
+    name: &str,
+    generated_prefix: &str,
+    candidates: impl Iterator<Item = (u32, &'a str)> + Clone,
+) -> Result<u32, NameError> {
+    if let Some(id) = name
+        .strip_prefix(generated_prefix)
+        .and_then(|c| u32::from_str_radix(c, 16).ok())
+    {
+        return Ok(id);
+    }
+    if let Some((id, _)) = candidates.clone().find(|c| c.1 == name) {
+        return Ok(id);
+    }
+
+    let lowercase = name.to_lowercase();
+    let mut matches = candidates
+        .clone()
+        .filter(|c| c.1.to_lowercase() == lowercase)
+        .collect::<Vec<_>>();
+    matches.dedup_by_key(|c| c.0);
+    if let [(id, _)] = matches.as_slice() {
+        return Ok(*id);
+    }
+
+    let mut suggestions = if matches.is_empty() {
+        let max_distance = (name.len() / 3).max(2);
+        let mut close = candidates
+            .map(|c| (edit_distance(&lowercase, &c.1.to_lowercase()), c.1))
+            .filter(|c| c.0 <= max_distance)
+            .collect::<Vec<_>>();
+        close.sort_by_key(|c| c.0);
+        close
+            .into_iter()
+            .map(|c| c.1.to_string())
+            .collect::<Vec<_>>()
+    } else {
+        // the name is ambiguous without its case
+        matches.into_iter().map(|c| c.1.to_string()).collect()
+    };
+    suggestions.dedup();
+    suggestions.truncate(3);
+    Err(NameError {
+        kind,
+        name: name.to_string(),
+        suggestions,
+    })
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let old = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(row[j + 1])
+            };
+            prev = old;
+        }
+    }
+    row[b.len()]
+}
@@ -1,5 +1,6 @@
 use bytestream::{ByteOrder, StreamWriter};
 use std::{
+    fmt::{self, Display},
     io::{self, Write},
     str::FromStr,
 };
@@ -8,54 +9,391 @@ use std::{
 pub struct Tempo {
     pub id: u32,
     pub data: Vec<TempoVal>,
+    /// Whether the tempo is for streamed (AAC) or sequenced (BCGRP) audio, if given explicitly
+    pub streamed: Option<bool>,
 }
 
 #[derive(Debug, Clone)]
 pub struct TempoVal {
     pub beats: f32,
     pub time: u32, // in 32000ths of a second
-    pub loop_val: u32,
+    pub loop_val: LoopFlags,
+}
+
+/// The flags stored in a [`TempoVal`]'s loop value
+///
+/// Only the bits that end a tempo are understood, any others are kept as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoopFlags(u32);
+
+impl LoopFlags {
+    /// Ends the tempo after the value it's in
+    pub const END: Self = Self(0x0001);
+    /// Ends the tempo after the value it's in, looping back to its start
+    pub const LOOP: Self = Self(0x8000);
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether the tempo stops being read after this value, whether it loops or not
+    pub const fn is_terminator(self) -> bool {
+        self.0 & (Self::END.0 | Self::LOOP.0) != 0
+    }
+}
+
+/// The loop value Tickompiler uses when a .tempo file doesn't give one
+///
+/// It's 2, a value that goes on to the next one. What that bit does isn't known, the game
+/// only looks at [`LoopFlags::END`] and [`LoopFlags::LOOP`] to find where a tempo ends.
+impl Default for LoopFlags {
+    fn default() -> Self {
+        Self(2)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TempoError {
+    /// Index of the tempo value with the problem
+    pub index: usize,
+    pub kind: TempoErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TempoErrorKind {
+    /// A value other than the last one ends the tempo, which would cut it short
+    TerminatorBeforeEnd,
+    /// The last value doesn't end the tempo, so the game would keep reading past it
+    Unterminated,
+}
+
+impl Display for TempoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            TempoErrorKind::TerminatorBeforeEnd => write!(
+                f,
+                "tempo value #{} ends the tempo, but it isn't the last one",
+                self.index
+            ),
+            TempoErrorKind::Unterminated => write!(
+                f,
+                "tempo value #{} is the last one, but it doesn't end the tempo",
+                self.index
+            ),
+        }
+    }
+}
+
+impl From<TempoError> for io::Error {
+    fn from(value: TempoError) -> Self {
+        Self::new(io::ErrorKind::InvalidData, value.to_string())
+    }
+}
+
+impl TempoVal {
+    /// Calculates the time (in 32000ths of a second) that the given beats take at a given BPM
+    ///
+    /// This is done with `f32`s in the same order Tickompiler does it, so .tempo files compile
+    /// to the same times they always have.
+    pub fn time_from_bpm(beats: f32, bpm: f32) -> u32 {
+        (beats / bpm * 60.0 * 32000.0) as u32
+    }
+
+    /// The BPM with the fewest digits that gives back exactly this value's time, if there is one
+    pub fn exact_bpm(&self) -> Option<f32> {
+        let bpm = self.bpm() as f32;
+        if !bpm.is_finite() || bpm <= 0.0 {
+            return None;
+        }
+        // the closest BPM can be a few steps off of the one that truncates to the right time
+        (bpm.to_bits().saturating_sub(4)..=bpm.to_bits() + 4)
+            .map(f32::from_bits)
+            .filter(|c| Self::time_from_bpm(self.beats, *c) == self.time)
+            .min_by_key(|c| c.to_string().len())
+    }
+
+    pub fn bpm(&self) -> f64 {
+        60.0 * 32000.0 * self.beats as f64 / self.time as f64
+    }
 }
 
 impl Tempo {
+    /// Whether the tempo is for streamed audio, defaulting to what the ID range suggests
+    /// if it's not set explicitly
     pub fn is_streamed(&self) -> bool {
-        //TODO: currently always makes custom tempo IDs AAC since they can't be called for BCGRPs
-        !(self.id >= 0x01000101 && self.id <= 0x01000281)
+        // custom IDs default to streamed, sequenced ones have to be marked as such
+        self.streamed
+            .unwrap_or(!(self.id >= 0x01000101 && self.id <= 0x01000281))
+    }
+}
+
+/// Time conversions, with times in 32000ths of a second like in [`TempoVal`]
+///
+/// Beats past the end of a looping tempo wrap back around to its start, while beats past the
+/// end of any other tempo keep going at its last BPM.
+impl Tempo {
+    pub fn loops(&self) -> bool {
+        self.data
+            .last()
+            .map(|c| c.loop_val.contains(LoopFlags::LOOP))
+            .unwrap_or(false)
+    }
+
+    pub fn total_beats(&self) -> f64 {
+        self.data.iter().map(|c| c.beats as f64).sum()
+    }
+
+    pub fn total_time(&self) -> u64 {
+        self.data.iter().map(|c| c.time as u64).sum()
+    }
+
+    pub fn total_seconds(&self) -> f64 {
+        self.total_time() as f64 / 32000.0
+    }
+
+    pub fn beats_to_time(&self, beats: f64) -> f64 {
+        let (mut beats, mut time) = (beats, 0.0);
+        let total_beats = self.total_beats();
+        if self.loops() && total_beats > 0.0 && beats >= total_beats {
+            let loops = (beats / total_beats).floor();
+            beats -= loops * total_beats;
+            time += loops * self.total_time() as f64;
+        }
+        for val in &self.data {
+            if beats < val.beats as f64 {
+                return time + beats * val.time as f64 / val.beats as f64;
+            }
+            beats -= val.beats as f64;
+            time += val.time as f64;
+        }
+        match self.data.last() {
+            Some(last) if last.beats > 0.0 => time + beats * last.time as f64 / last.beats as f64,
+            _ => time,
+        }
+    }
+
+    pub fn time_to_beats(&self, time: f64) -> f64 {
+        let (mut time, mut beats) = (time, 0.0);
+        let total_time = self.total_time() as f64;
+        if self.loops() && total_time > 0.0 && time >= total_time {
+            let loops = (time / total_time).floor();
+            time -= loops * total_time;
+            beats += loops * self.total_beats();
+        }
+        for val in &self.data {
+            if time < val.time as f64 {
+                return beats + time * val.beats as f64 / val.time as f64;
+            }
+            time -= val.time as f64;
+            beats += val.beats as f64;
+        }
+        match self.data.last() {
+            Some(last) if last.time > 0 => beats + time * last.beats as f64 / last.time as f64,
+            _ => beats,
+        }
+    }
+
+    pub fn beats_to_seconds(&self, beats: f64) -> f64 {
+        self.beats_to_time(beats) / 32000.0
+    }
+
+    pub fn seconds_to_beats(&self, seconds: f64) -> f64 {
+        self.time_to_beats(seconds * 32000.0)
+    }
+
+    /// The BPM the tempo is at on a given beat, or `None` if the tempo has no values
+    pub fn bpm_at(&self, beats: f64) -> Option<f64> {
+        let mut beats = beats;
+        let total_beats = self.total_beats();
+        if self.loops() && total_beats > 0.0 && beats >= total_beats {
+            beats %= total_beats;
+        }
+        for val in &self.data {
+            if beats < val.beats as f64 {
+                return Some(val.bpm());
+            }
+            beats -= val.beats as f64;
+        }
+        self.data.last().map(TempoVal::bpm)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TempoParseError {
+    pub line: usize,
+    pub column: usize,
+    pub expected: TempoField,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TempoField {
+    Id,
+    AudioType,
+    Bpm,
+    Beats,
+    LoopVal,
+    MidLoopVal,
+    LastLoopVal,
+    EndOfLine,
+}
+
+impl Display for TempoField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Id => "hex ID",
+            Self::AudioType => "'streamed' or 'sequenced'",
+            Self::Bpm => "BPM float or @raw time",
+            Self::Beats => "beats float",
+            Self::LoopVal => "loop value",
+            Self::MidLoopVal => {
+                "loop value without the end/loop flags, as this isn't the last line"
+            }
+            Self::LastLoopVal => "loop value that ends the tempo (1, or 0x8001 to loop)",
+            Self::EndOfLine => "end of line",
+        })
+    }
+}
+
+impl Display for TempoParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: expected {}",
+            self.line, self.column, self.expected
+        )
+    }
+}
+
+impl From<TempoParseError> for io::Error {
+    fn from(value: TempoParseError) -> Self {
+        Self::new(io::ErrorKind::InvalidData, value.to_string())
     }
 }
 
 impl Tempo {
-    const LOOP_VAL_DEFAULT: u32 = 2;
-
-    pub fn from_tickompiler_file(tempo: String) -> Option<Self> {
-        let mut lines = tempo.lines();
-        let id = match u32::from_str_radix(lines.next()?, 16) {
-            Ok(c) => c,
-            Err(_) => return None,
-        };
+    /// Checks that the loop values of the tempo make sense together
+    pub fn validate(&self) -> Result<(), TempoError> {
+        for (index, val) in self.data.iter().enumerate() {
+            let is_last = index == self.data.len() - 1;
+            if val.loop_val.is_terminator() && !is_last {
+                Err(TempoError {
+                    index,
+                    kind: TempoErrorKind::TerminatorBeforeEnd,
+                })?
+            }
+            if !val.loop_val.is_terminator() && is_last {
+                Err(TempoError {
+                    index,
+                    kind: TempoErrorKind::Unterminated,
+                })?
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses a Tickompiler .tempo file
+    ///
+    /// The first line holds the tempo ID in hex, and every line after that is made of a BPM,
+    /// an amount of beats and an optional loop value (decimal, or hex with `0x`). If the last line
+    /// leaves out its loop value, it ends the tempo. Comments start with `//`, and blank lines are
+    /// skipped.
+    ///
+    /// The ID can be followed by `streamed` or `sequenced` to say which kind of audio the tempo
+    /// is for, instead of guessing it from the ID.
+    ///
+    /// Instead of a BPM, the time a line takes can be given directly, in 32000ths of a second,
+    /// by prefixing it with `@` (for example, `@64000 4` is the same as `120 4`).
+    pub fn from_tickompiler_file(tempo: &str) -> Result<Self, TempoParseError> {
+        let mut id = None;
+        let mut streamed = None;
         let mut data = vec![];
+        // where each value's loop value is, to point to it if it's wrong
+        let mut loop_positions = vec![];
+        let mut line_num = 0;
+        let mut last_loop_omitted = false;
 
-        for line in lines {
-            let (tempo_str, line) = line.split_once(' ')?;
-            let tempo = match f32::from_str(tempo_str) {
-                Ok(c) => c,
-                Err(_) => return None,
+        for (i, line) in tempo.lines().enumerate() {
+            line_num = i + 1;
+            let line = line.trim_end_matches('\r');
+            let line = match line.find("//") {
+                Some(c) => &line[..c],
+                None => line,
             };
-            let (beats_str, line) = match line.split_once(' ') {
-                Some(c) => c,
-                None => (line, ""),
+            let mut fields = split_fields(line).into_iter();
+            let end_column = line.chars().count() + 1;
+            let error = |column, expected| TempoParseError {
+                line: line_num,
+                column,
+                expected,
+            };
+
+            let Some(first) = fields.next() else {
+                continue;
             };
-            let beats = f32::from_str(beats_str).ok()?;
-            let loop_val = if line.is_empty() {
-                Self::LOOP_VAL_DEFAULT
-            } else {
-                let (loop_str, _) = match line.split_once(' ') {
-                    Some(c) => c,
-                    None => (line, ""),
+
+            if id.is_none() {
+                id = Some(
+                    u32::from_str_radix(first.1.trim_start_matches("0x"), 16)
+                        .map_err(|_| error(first.0, TempoField::Id))?,
+                );
+                streamed = match fields.next() {
+                    Some((_, "streamed")) => Some(true),
+                    Some((_, "sequenced")) => Some(false),
+                    Some((column, _)) => Err(error(column, TempoField::AudioType))?,
+                    None => None,
                 };
-                u32::from_str(loop_str).ok()?
+                if let Some((column, _)) = fields.next() {
+                    Err(error(column, TempoField::EndOfLine))?
+                }
+                continue;
+            }
+
+            let raw_time = match first.1.strip_prefix('@') {
+                Some(c) => Some(u32::from_str(c).map_err(|_| error(first.0, TempoField::Bpm))?),
+                None => None,
+            };
+            let tempo = match raw_time {
+                Some(_) => 0.0,
+                None => f32::from_str(first.1).map_err(|_| error(first.0, TempoField::Bpm))?,
+            };
+            let beats = match fields.next() {
+                Some((column, c)) => {
+                    f32::from_str(c).map_err(|_| error(column, TempoField::Beats))?
+                }
+                None => Err(error(end_column, TempoField::Beats))?,
+            };
+            let loop_val = match fields.next() {
+                Some((column, c)) => {
+                    loop_positions.push((line_num, column));
+                    last_loop_omitted = false;
+                    let bits = match c.strip_prefix("0x") {
+                        Some(c) => u32::from_str_radix(c, 16),
+                        None => u32::from_str(c),
+                    };
+                    LoopFlags::from_bits(bits.map_err(|_| error(column, TempoField::LoopVal))?)
+                }
+                None => {
+                    loop_positions.push((line_num, end_column));
+                    last_loop_omitted = true;
+                    LoopFlags::default()
+                }
+            };
+            if let Some((column, _)) = fields.next() {
+                Err(error(column, TempoField::EndOfLine))?
+            }
+
+            let time = match raw_time {
+                Some(c) => c,
+                None => TempoVal::time_from_bpm(beats, tempo),
             };
-            let time: u32 = (beats / tempo * 60.0 * 32000.0) as u32;
             data.push(TempoVal {
                 beats,
                 time,
@@ -63,8 +401,54 @@ impl Tempo {
             })
         }
 
-        Some(Self { id, data })
+        if let (true, Some(last)) = (last_loop_omitted, data.last_mut()) {
+            last.loop_val = LoopFlags::END;
+        }
+
+        match id {
+            Some(id) => {
+                let tempo = Self { id, data, streamed };
+                tempo.validate().map_err(|e| {
+                    let (line, column) = loop_positions[e.index];
+                    TempoParseError {
+                        line,
+                        column,
+                        expected: match e.kind {
+                            TempoErrorKind::TerminatorBeforeEnd => TempoField::MidLoopVal,
+                            TempoErrorKind::Unterminated => TempoField::LastLoopVal,
+                        },
+                    }
+                })?;
+                Ok(tempo)
+            }
+            None => Err(TempoParseError {
+                line: line_num.max(1),
+                column: 1,
+                expected: TempoField::Id,
+            }),
+        }
+    }
+}
+
+/// Splits a line by whitespace, keeping the (1-based) column each field starts at
+fn split_fields(line: &str) -> Vec<(usize, &str)> {
+    let mut out = vec![];
+    let mut start = None;
+    for (column, (i, c)) in line
+        .char_indices()
+        .chain(std::iter::once((line.len(), ' ')))
+        .enumerate()
+    {
+        match start {
+            None if !c.is_whitespace() => start = Some((column + 1, i)),
+            Some((start_column, start_i)) if c.is_whitespace() => {
+                out.push((start_column, &line[start_i..i]));
+                start = None;
+            }
+            _ => (),
+        }
     }
+    out
 }
 
 impl StreamWriter for Tempo {
@@ -78,7 +462,7 @@ impl StreamWriter for Tempo {
                 ByteOrder::LittleEndian => value.beats.to_le_bytes(),
             })?;
             value.time.write_to(buffer, order)?;
-            value.loop_val.write_to(buffer, order)?;
+            value.loop_val.bits().write_to(buffer, order)?;
         }
         Ok(())
     }
@@ -0,0 +1,216 @@
+//! Block syntax for conditionals and loops, lowered to if/else/endif and label/goto
+//!
+//! ```text
+//! if_gt 3 {
+//!     ...
+//! } else {
+//!     ...
+//! }
+//! while if_lt 5 {
+//!     ...
+//! }
+//! repeat 4 {
+//!     ...
+//! }
+//! ```
+//!
+//! Loops need `label`/`goto` IDs, which are written as placeholders and only given a number by
+//! [`allocate_label_ids`] once all the source is known, so they never collide with IDs the author
+//! used.
+
+use super::{
+    commands::TICKOMPILER_COMMANDS,
+    expr::Expr,
+    preprocess::{replace_identifiers, split_args, split_comment, split_head},
+};
+use std::{collections::HashSet, io};
+
+const BLOCK_LABEL_PREFIX: &str = "__block_";
+
+#[derive(Debug, Clone)]
+enum Block {
+    If { has_else: bool },
+    While { id: usize },
+    Repeat { id: usize },
+}
+
+/// What a line means for block syntax
+pub enum BlockLine<'a> {
+    /// Opens a block, with what comes before the `{`
+    Open(&'a str),
+    /// `} else {`
+    Else,
+    /// `}`
+    Close,
+}
+
+impl<'a> BlockLine<'a> {
+    pub fn parse(code: &'a str) -> Option<Self> {
+        let code = code.trim();
+        if code == "}" {
+            return Some(Self::Close);
+        }
+        let opener = code.strip_suffix('{')?.trim_end();
+        match opener.strip_prefix('}') {
+            Some(c) if c.trim() == "else" => Some(Self::Else),
+            Some(_) => None,
+            None => Some(Self::Open(opener)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Blocks {
+    stack: Vec<Block>,
+    next_id: usize,
+}
+
+fn error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+impl Blocks {
+    /// How many blocks are open
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Lowers the start of a block to the commands it stands for
+    pub fn open(&mut self, opener: &str) -> io::Result<Vec<String>> {
+        let (keyword, rest) = split_head(opener);
+        match keyword {
+            "while" => {
+                check_condition(rest)?;
+                let id = self.new_id();
+                self.stack.push(Block::While { id });
+                Ok(vec![format!("label {}", placeholder(id)), rest.to_string()])
+            }
+            "repeat" => {
+                if rest.is_empty() {
+                    Err(error(
+                        "repeat needs a number of times to repeat".to_string(),
+                    ))?
+                }
+                let id = self.new_id();
+                self.stack.push(Block::Repeat { id });
+                // the condvar is the loop counter, and it's saved around the body so the body
+                // can use it freely
+                Ok(vec![
+                    "push_condvar".to_string(),
+                    "set_condvar 0".to_string(),
+                    format!("label {}", placeholder(id)),
+                    format!("if_lt {rest}"),
+                    "push_condvar".to_string(),
+                ])
+            }
+            _ => {
+                check_condition(opener)?;
+                self.stack.push(Block::If { has_else: false });
+                Ok(vec![opener.to_string()])
+            }
+        }
+    }
+
+    pub fn else_branch(&mut self) -> io::Result<Vec<String>> {
+        match self.stack.last_mut() {
+            Some(Block::If { has_else: false }) => {
+                self.stack.pop();
+                self.stack.push(Block::If { has_else: true });
+                Ok(vec!["else".to_string()])
+            }
+            _ => Err(error("'} else {' doesn't close an if block".to_string())),
+        }
+    }
+
+    /// Lowers the `}` that ends the innermost block
+    pub fn close(&mut self) -> io::Result<Vec<String>> {
+        Ok(match self.stack.pop() {
+            Some(Block::If { .. }) => vec!["endif".to_string()],
+            Some(Block::While { id }) => {
+                vec![format!("goto {}", placeholder(id)), "endif".to_string()]
+            }
+            Some(Block::Repeat { id }) => vec![
+                "pop_condvar".to_string(),
+                "add_condvar 1".to_string(),
+                format!("goto {}", placeholder(id)),
+                "endif".to_string(),
+                "pop_condvar".to_string(),
+            ],
+            None => Err(error("'}' without a block to close".to_string()))?,
+        })
+    }
+
+    fn new_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+}
+
+fn placeholder(id: usize) -> String {
+    format!("{BLOCK_LABEL_PREFIX}{id}")
+}
+
+/// Makes sure a block's condition is one of the if commands
+fn check_condition(cond: &str) -> io::Result<()> {
+    let name = split_head(cond).0;
+    let name = name.split_once('<').map(|c| c.0).unwrap_or(name);
+    let is_if = TICKOMPILER_COMMANDS
+        .iter()
+        .any(|c| c.0 == name && c.1.num == 0x16)
+        || name.eq_ignore_ascii_case("0x16");
+    if is_if {
+        Ok(())
+    } else {
+        Err(error(format!(
+            "a block's condition must be an if command, not '{cond}'"
+        )))
+    }
+}
+
+/// Gives every block label placeholder in preprocessed source a `label`/`goto` ID that's not
+/// used anywhere else in it
+pub fn allocate_label_ids(text: &str) -> String {
+    let mut used = HashSet::new();
+    for line in text.lines() {
+        let (head, args) = split_head(split_comment(line).0.trim());
+        let (name, arg0) = match head.split_once('<') {
+            Some((name, arg0)) => (name, Some(arg0.strip_suffix('>').unwrap_or(arg0))),
+            None => (head, None),
+        };
+        let is_label = ["label", "goto", "0x14", "0x15"]
+            .iter()
+            .any(|c| name.eq_ignore_ascii_case(c));
+        if !is_label {
+            continue;
+        }
+        let id = match arg0 {
+            Some(c) => c,
+            None => split_args(args).first().copied().unwrap_or(""),
+        };
+        if let Ok(Expr::Num(c)) = Expr::parse(id) {
+            used.insert(c);
+        }
+    }
+
+    let mut ids = vec![];
+    let mut next = 0;
+    let mut out = String::with_capacity(text.len());
+    for line in text.lines() {
+        out.push_str(&replace_identifiers(line, true, |word| {
+            let block = word
+                .strip_prefix(BLOCK_LABEL_PREFIX)?
+                .parse::<usize>()
+                .ok()?;
+            while ids.len() <= block {
+                while used.contains(&next) {
+                    next += 1;
+                }
+                ids.push(next);
+                next += 1;
+            }
+            Some(ids[block].to_string())
+        }));
+        out.push('\n');
+    }
+    out
+}
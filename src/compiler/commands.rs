@@ -1,5 +1,6 @@
 use std::ops::RangeInclusive;
 
+use super::expr;
 use tickflow_parse::old::ParsedValue;
 
 #[derive(Clone)]
@@ -166,8 +167,12 @@ pub fn resolve_command(
                             })?
                         }
                     }
-                    ParsedValue::Label(_) => {
-                        if matches!(def.args[i], Label) {
+                    ParsedValue::Label(c) => {
+                        // expressions can be used anywhere an integer is, even if they use labels
+                        if matches!(def.args[i], Label)
+                            || (expr::placeholder_index(c).is_some()
+                                && matches!(def.args[i], Int | Opt(_)))
+                        {
                             new_args.push(arg)
                         } else {
                             Err(ResolveError::WrongArgType {
@@ -330,3 +335,19 @@ pub const TICKOMPILER_COMMANDS: &[(&str, CmdDef)] = &[
     CmdDef::named("debug", 0xB5, Any, &[AString]),
     CmdDef::named("random", 0xB8, Argument, &[]),
 ];
+
+/// Arguments (by command name and index) that hold a sub, category, engine or sound ID,
+/// so the decompiler can write them as constant names
+pub const ID_ARGS: &[(&str, usize)] = &[
+    ("async_sub", 0),
+    ("get_async", 0),
+    ("kill_cat", 0),
+    ("kill_sub", 0),
+    ("sub", 0),
+    ("get_sync", 0),
+    ("set_cat", 0),
+    ("engine", 0),
+    ("play_sfx", 0),
+    ("set_sfx", 0),
+    ("remove_sfx", 0),
+];
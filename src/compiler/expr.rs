@@ -0,0 +1,325 @@
+//! Constant expressions over integers, named constants and label offsets
+
+use super::preprocess::display_label;
+use crate::c00::NameError;
+use std::fmt;
+
+/// Prefix of the labels that stand in for expressions that can only be evaluated after layout
+pub const PLACEHOLDER_PREFIX: &str = "__expr_";
+
+pub fn placeholder(index: usize) -> String {
+    format!("{PLACEHOLDER_PREFIX}{index}")
+}
+
+/// Gets the expression index out of a placeholder label
+pub fn placeholder_index(label: &str) -> Option<usize> {
+    label.strip_prefix(PLACEHOLDER_PREFIX)?.parse().ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Shl,
+    Shr,
+    Or,
+    And,
+}
+
+impl BinOp {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Shl => "<<",
+            Self::Shr => ">>",
+            Self::Or => "|",
+            Self::And => "&",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(i32),
+    /// A named constant or a label
+    Name(String),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+/// The result of an expression
+///
+/// `labels` counts how many label offsets were added in, minus how many were subtracted. A
+/// difference of two labels is a plain number, while a label plus a number is still a pointer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Value {
+    pub value: i32,
+    pub labels: i32,
+}
+
+impl Value {
+    pub fn int(value: i32) -> Self {
+        Self { value, labels: 0 }
+    }
+
+    pub fn pointer(value: i32) -> Self {
+        Self { value, labels: 1 }
+    }
+
+    pub fn is_pointer(&self) -> bool {
+        self.labels == 1
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprError {
+    Syntax(String),
+    Undefined(String),
+    DivisionByZero,
+    /// A label was used in an operation other than adding or subtracting
+    LabelOperation(BinOp),
+    /// The result isn't a plain number or an offset from a single label
+    LabelCount(i32),
+    BuiltinName(NameError),
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Syntax(c) => write!(f, "Invalid expression: {c}"),
+            Self::Undefined(c) => {
+                write!(f, "Could not find label or constant {}", display_label(c))
+            }
+            Self::DivisionByZero => write!(f, "Division by zero in expression"),
+            Self::LabelOperation(op) => write!(
+                f,
+                "Labels can only be added or subtracted, not used with '{}'",
+                op.symbol()
+            ),
+            Self::LabelCount(c) => write!(
+                f,
+                "Expression adds up to {c} labels, but only a number or a single label offset is allowed"
+            ),
+            Self::BuiltinName(c) => write!(f, "{c}"),
+        }
+    }
+}
+
+impl From<NameError> for ExprError {
+    fn from(value: NameError) -> Self {
+        Self::BuiltinName(value)
+    }
+}
+
+impl From<ExprError> for std::io::Error {
+    fn from(value: ExprError) -> Self {
+        Self::new(std::io::ErrorKind::Other, value.to_string())
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, ExprError> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.expr(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(c) => Err(ExprError::Syntax(format!("unexpected {c:?} in '{text}'"))),
+        }
+    }
+
+    /// Every name the expression uses, in order
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Self::Num(_) => vec![],
+            Self::Name(c) => vec![c],
+            Self::Neg(c) => c.names(),
+            Self::Binary(_, a, b) => {
+                let mut out = a.names();
+                out.extend(b.names());
+                out
+            }
+        }
+    }
+
+    /// Replaces the names that `f` gives a value for
+    pub fn resolve(
+        self,
+        f: &impl Fn(&str) -> Result<Option<i32>, ExprError>,
+    ) -> Result<Self, ExprError> {
+        Ok(match self {
+            Self::Name(c) => match f(&c)? {
+                Some(v) => Self::Num(v),
+                None => Self::Name(c),
+            },
+            Self::Neg(c) => Self::Neg(Box::new(c.resolve(f)?)),
+            Self::Binary(op, a, b) => {
+                Self::Binary(op, Box::new(a.resolve(f)?), Box::new(b.resolve(f)?))
+            }
+            c => c,
+        })
+    }
+
+    pub fn eval(&self, lookup: &impl Fn(&str) -> Option<Value>) -> Result<Value, ExprError> {
+        let value = self.eval_inner(lookup)?;
+        if value.labels != 0 && value.labels != 1 {
+            Err(ExprError::LabelCount(value.labels))?
+        }
+        Ok(value)
+    }
+
+    fn eval_inner(&self, lookup: &impl Fn(&str) -> Option<Value>) -> Result<Value, ExprError> {
+        Ok(match self {
+            Self::Num(c) => Value::int(*c),
+            Self::Name(c) => lookup(c).ok_or_else(|| ExprError::Undefined(c.clone()))?,
+            Self::Neg(c) => {
+                let c = c.eval_inner(lookup)?;
+                Value {
+                    value: c.value.wrapping_neg(),
+                    labels: -c.labels,
+                }
+            }
+            Self::Binary(op, a, b) => {
+                let a = a.eval_inner(lookup)?;
+                let b = b.eval_inner(lookup)?;
+                let labels = match op {
+                    BinOp::Add => a.labels + b.labels,
+                    BinOp::Sub => a.labels - b.labels,
+                    _ if a.labels != 0 || b.labels != 0 => Err(ExprError::LabelOperation(*op))?,
+                    _ => 0,
+                };
+                let value = match op {
+                    BinOp::Add => a.value.wrapping_add(b.value),
+                    BinOp::Sub => a.value.wrapping_sub(b.value),
+                    BinOp::Mul => a.value.wrapping_mul(b.value),
+                    BinOp::Div if b.value == 0 => Err(ExprError::DivisionByZero)?,
+                    BinOp::Div => a.value.wrapping_div(b.value),
+                    BinOp::Shl => a.value.wrapping_shl(b.value as u32),
+                    BinOp::Shr => a.value.wrapping_shr(b.value as u32),
+                    BinOp::Or => a.value | b.value,
+                    BinOp::And => a.value & b.value,
+                };
+                Value { value, labels }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i32),
+    Name(String),
+    Op(BinOp),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ExprError> {
+    let mut out = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '(' => out.push(Token::Open),
+            ')' => out.push(Token::Close),
+            '+' => out.push(Token::Op(BinOp::Add)),
+            '-' => out.push(Token::Op(BinOp::Sub)),
+            '*' => out.push(Token::Op(BinOp::Mul)),
+            '/' => out.push(Token::Op(BinOp::Div)),
+            '|' => out.push(Token::Op(BinOp::Or)),
+            '&' => out.push(Token::Op(BinOp::And)),
+            '<' | '>' => {
+                if chars.next().map(|c| c.1) != Some(c) {
+                    Err(ExprError::Syntax(format!("expected '{c}{c}' in '{text}'")))?
+                }
+                out.push(Token::Op(if c == '<' { BinOp::Shl } else { BinOp::Shr }))
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '.' => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, c)) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || *c == '_' || *c == '.') {
+                        break;
+                    }
+                    end = j + c.len_utf8();
+                    chars.next();
+                }
+                let word = &text[i..end];
+                if c.is_ascii_digit() {
+                    let num = match word.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16).map(|c| c as i32),
+                        None => word.parse::<u32>().map(|c| c as i32),
+                    };
+                    out.push(Token::Num(num.map_err(|_| {
+                        ExprError::Syntax(format!("invalid number {word}"))
+                    })?));
+                } else {
+                    out.push(Token::Name(word.to_string()));
+                }
+            }
+            c => Err(ExprError::Syntax(format!(
+                "unexpected character '{c}' in '{text}'"
+            )))?,
+        }
+    }
+    Ok(out)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    /// Binding strength of each operator, from loosest to tightest
+    fn precedence(op: BinOp) -> u8 {
+        match op {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Shl | BinOp::Shr => 3,
+            BinOp::Add | BinOp::Sub => 4,
+            BinOp::Mul | BinOp::Div => 5,
+        }
+    }
+
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, ExprError> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Op(op)) = self.tokens.get(self.pos) {
+            let op = *op;
+            let precedence = Self::precedence(op);
+            if precedence <= min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expr(precedence)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(c)) => Ok(Expr::Num(c)),
+            Some(Token::Name(c)) => Ok(Expr::Name(c)),
+            Some(Token::Op(BinOp::Sub)) => Ok(Expr::Neg(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.expr(0)?;
+                if self.tokens.get(self.pos) != Some(&Token::Close) {
+                    Err(ExprError::Syntax("missing ')'".to_string()))?
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(c) => Err(ExprError::Syntax(format!("unexpected {c:?}"))),
+            None => Err(ExprError::Syntax(
+                "unexpected end of expression".to_string(),
+            )),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use tickflow_parse::old::{CommandName, ParsedStatement, ParsedValue};
+
+/// Size in bytes that a resolved command takes up in the tickflow, without annotations
+pub fn command_size(cmd: u16, arg0: u32, args: &[ParsedValue]) -> usize {
+    if cmd != 0xFFFF {
+        4 * (1 + args.len())
+    } else if arg0 == 0 {
+        // bytes, padded to 4
+        args.len() + (4 - args.len() % 4) % 4
+    } else if arg0 == 1 {
+        // ints
+        args.len() * 4
+    } else {
+        unreachable!();
+    }
+}
+
+/// Positions of every label in a resolved command stream, worked out in one pass
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    pub labels: HashMap<String, i32>,
+    /// Size of all the tickflow, which is also where the string data starts
+    pub size: usize,
+}
+
+impl Layout {
+    pub fn new(cmds: &[ParsedStatement]) -> Self {
+        let mut out = Self::default();
+        for statement in cmds {
+            match statement {
+                ParsedStatement::Label(name, _) => {
+                    // duplicates are reported elsewhere, the first definition wins
+                    out.labels.entry(name.clone()).or_insert(out.size as i32);
+                }
+                ParsedStatement::Command {
+                    cmd: CommandName::Raw(cmd),
+                    arg0,
+                    args,
+                } => out.size += command_size(*cmd as u16, arg0.unwrap_or(0), args),
+                _ => (),
+            }
+        }
+        out
+    }
+
+    pub fn label(&self, name: &str) -> Option<i32> {
+        self.labels.get(name).copied()
+    }
+}
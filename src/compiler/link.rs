@@ -0,0 +1,176 @@
+//! Linking object modules into a single tickflow file
+//!
+//! Modules are laid out one after another in the order they're given, and their string data is
+//! merged into one pool. The first module is the game itself, so the linked file gets its
+//! `#index`. The start and assets can be in any module, but only in one.
+
+use super::{object::Object, CompiledFileType};
+use crate::{
+    tickflow::{self, Statement, StringPool, Tickflow, ANN_POINTER, ANN_STRING, ANN_USTRING},
+    BTKS,
+};
+use bytestream::{ByteOrder::LittleEndian as LE, StreamWriter};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+/// Links object module files into a Tickompiler .bin or a BTKS file
+pub fn link_files(
+    modules: &[PathBuf],
+    out: impl AsRef<Path>,
+    out_filetype: CompiledFileType,
+) -> io::Result<()> {
+    let mut objects = vec![];
+    for path in modules {
+        let object = Object::read_from(&mut File::open(path)?).map_err(|e| {
+            io::Error::new(e.kind(), format!("couldn't read {}: {e}", path.display()))
+        })?;
+        objects.push((path.display().to_string(), object));
+    }
+    let bin = link(&objects)?;
+    let mut out = File::create(out)?;
+    match out_filetype {
+        CompiledFileType::Tickompiler => out.write_all(&bin),
+        CompiledFileType::BTKS => {
+            let size = bin.len() as u64;
+            BTKS::from_tickompiler_binary(&mut Cursor::new(bin), size, vec![], false)?
+                .to_btks_file(&mut out)
+        }
+        CompiledFileType::Object => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "modules can only be linked into a .bin or BTKS file",
+        )),
+    }
+}
+
+/// Links named object modules into a Tickompiler .bin
+pub fn link(modules: &[(String, Object)]) -> io::Result<Vec<u8>> {
+    let error = |msg: String| io::Error::new(io::ErrorKind::Other, msg);
+
+    let mut tickflows = vec![];
+    let mut bases = vec![];
+    let mut size = 0;
+    for (_, object) in modules {
+        let tickflow = Tickflow::from_bin(&mut object.bin.as_slice())?;
+        bases.push(size);
+        size += tickflow.strings_offset;
+        tickflows.push(tickflow);
+    }
+
+    let mut symbols: HashMap<&str, (u32, &str)> = HashMap::new();
+    for ((name, object), base) in modules.iter().zip(&bases) {
+        for (label, offset) in &object.symbols {
+            if let Some((_, other)) = symbols.insert(label, (base + offset, name)) {
+                Err(error(format!(
+                    "label {label} is defined in both {other} and {name}"
+                )))?
+            }
+        }
+    }
+
+    // where the start and assets are, out of the modules that have them
+    let mut header = [None, None];
+    for (((name, _), tickflow), base) in modules.iter().zip(&tickflows).zip(&bases) {
+        for (i, (offset, what)) in [(tickflow.start, "start"), (tickflow.assets, "assets")]
+            .into_iter()
+            .enumerate()
+        {
+            if offset == u32::MAX {
+                continue;
+            }
+            if let Some((_, other)) = header[i].replace((base + offset, name)) {
+                Err(error(format!("both {other} and {name} have {what}")))?
+            }
+        }
+    }
+    let [Some(start), Some(assets)] = header.map(|c| c.map(|c| c.0)) else {
+        let missing = if header[0].is_none() {
+            "start"
+        } else {
+            "assets"
+        };
+        Err(error(format!("none of the modules have {missing}")))?
+    };
+
+    let mut out = vec![];
+    let index = tickflows.first().map(|c| c.index).unwrap_or_default();
+    index.write_to(&mut out, LE)?;
+    start.write_to(&mut out, LE)?;
+    assets.write_to(&mut out, LE)?;
+
+    let mut str_data = StringPool::new();
+    for (((name, object), tickflow), base) in modules.iter().zip(&tickflows).zip(&bases) {
+        let relocations = object
+            .relocations
+            .iter()
+            .map(|c| ((c.offset, c.arg), c.label.as_str()))
+            .collect::<HashMap<_, _>>();
+        for statement in &tickflow.statements {
+            let (offset, cmd, arg0, args, anns) = match statement {
+                Statement::Data { data, .. } => {
+                    (-1i32).write_to(&mut out, LE)?;
+                    1.write_to(&mut out, LE)?;
+                    let ann = tickflow::ANN_DATA + ((data.len() as u32) << 8);
+                    ann.write_to(&mut out, LE)?;
+                    out.extend(data);
+                    out.extend(vec![0; (4 - data.len() % 4) % 4]);
+                    continue;
+                }
+                Statement::Command {
+                    offset,
+                    cmd,
+                    arg0,
+                    args,
+                    anns,
+                } => (*offset, *cmd, *arg0, args, anns),
+            };
+            let mut args = args.clone();
+            for (i, ann) in anns {
+                let arg = &mut args[*i as usize];
+                match *ann {
+                    ANN_POINTER => match relocations.get(&(offset, *i)) {
+                        Some(label) => {
+                            let (address, _) = symbols.get(label).ok_or_else(|| {
+                                error(format!(
+                                    "label {label} used in {name} isn't in any of the modules"
+                                ))
+                            })?;
+                            *arg = arg.wrapping_add(*address);
+                        }
+                        None => *arg = arg.wrapping_add(*base),
+                    },
+                    ANN_STRING | ANN_USTRING => {
+                        let is_unicode = *ann == ANN_USTRING;
+                        let chars =
+                            tickflow.string_chars_at(*arg, is_unicode).ok_or_else(|| {
+                                error(format!(
+                                    "string pointer in {name} points outside of its string data"
+                                ))
+                            })?;
+                        *arg = size + str_data.add_encoded(chars, is_unicode);
+                    }
+                    _ => (),
+                }
+            }
+
+            if !anns.is_empty() {
+                (-1i32).write_to(&mut out, LE)?;
+                (anns.len() as u32).write_to(&mut out, LE)?;
+                for (i, ann) in anns {
+                    ((i << 8) + ann).write_to(&mut out, LE)?;
+                }
+            }
+            let op_int = (cmd & 0x3FF) as u32 + ((args.len() as u32 & 0xF) << 10) + (arg0 << 14);
+            op_int.write_to(&mut out, LE)?;
+            for arg in args {
+                arg.write_to(&mut out, LE)?;
+            }
+        }
+    }
+    (-2i32).write_to(&mut out, LE)?;
+    out.extend(str_data.data());
+    Ok(out)
+}
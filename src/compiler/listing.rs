@@ -0,0 +1,120 @@
+//! Listing files, which show where everything in a source file ends up in the compiled tickflow
+//!
+//! ```text
+//! 00000000                                      a.tickflow:4  start:
+//! 00000000  00004401 00000014                   a.tickflow:5  0x1<1> data
+//! 00000008  00000008                            a.tickflow:6  stop
+//! ```
+//!
+//! Every line has the offset, the encoded words (the op word, `cmd | argc<<10 | arg0<<14`,
+//! followed by the arguments) and the source line they came from. The string data is laid out
+//! at the end.
+
+use super::preprocess::{display_label, SourceLocation};
+use crate::tickflow::{self, StringPool};
+use std::io::{self, Write};
+
+/// How many words go on a line before the rest are put on the lines after it
+const WORDS_PER_LINE: usize = 4;
+
+pub struct Listing<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Listing<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn label(
+        &mut self,
+        offset: usize,
+        name: &str,
+        location: Option<&SourceLocation>,
+    ) -> io::Result<()> {
+        let source = match location {
+            Some(c) => source(c),
+            // labels made by a macro share their line with the rest of the macro
+            None => format!("{}:", display_label(name)),
+        };
+        self.row(offset, &[], &source)
+    }
+
+    pub fn command(
+        &mut self,
+        offset: usize,
+        words: &[u32],
+        location: Option<&SourceLocation>,
+    ) -> io::Result<()> {
+        self.row(offset, words, &location.map(source).unwrap_or_default())
+    }
+
+    /// Lists a data block, padding included
+    pub fn data(
+        &mut self,
+        offset: usize,
+        data: &[u8],
+        location: Option<&SourceLocation>,
+    ) -> io::Result<()> {
+        self.command(offset, &to_words(data), location)
+    }
+
+    /// Lists the string data, which starts at `offset`
+    pub fn strings(&mut self, offset: usize, pool: &StringPool) -> io::Result<()> {
+        writeln!(self.out, "\n; string data")?;
+        let strings = pool.strings().collect::<Vec<_>>();
+        for (i, &(pos, is_unicode, chars)) in strings.iter().enumerate() {
+            let end = strings
+                .get(i + 1)
+                .map_or(pool.data().len(), |c| c.0 as usize);
+            let text = format!(
+                "{}\"{}\"",
+                if is_unicode { "u" } else { "" },
+                escape(&tickflow::decode_string(chars, is_unicode))
+            );
+            let words = to_words(&pool.data()[pos as usize..end]);
+            self.row(offset + pos as usize, &words, &text)?;
+        }
+        Ok(())
+    }
+
+    fn row(&mut self, offset: usize, words: &[u32], source: &str) -> io::Result<()> {
+        let width = WORDS_PER_LINE * 9 - 1;
+        let mut chunks = words.chunks(WORDS_PER_LINE);
+        let first = chunks.next().unwrap_or_default();
+        let line = format!("{:08X}  {:width$}  {}", offset, join_words(first), source);
+        writeln!(self.out, "{}", line.trim_end())?;
+        for (i, chunk) in chunks.enumerate() {
+            let offset = offset + 4 * WORDS_PER_LINE * (i + 1);
+            writeln!(self.out, "{:08X}  {}", offset, join_words(chunk))?;
+        }
+        Ok(())
+    }
+}
+
+fn source(location: &SourceLocation) -> String {
+    format!("{location}  {}", location.text)
+}
+
+fn join_words(words: &[u32]) -> String {
+    words
+        .iter()
+        .map(|c| format!("{c:08X}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits data into little-endian words, with the last one padded with zeroes
+fn to_words(data: &[u8]) -> Vec<u32> {
+    data.chunks(4)
+        .map(|c| {
+            let mut word = [0; 4];
+            word[..c.len()].copy_from_slice(c);
+            u32::from_le_bytes(word)
+        })
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -1,22 +1,65 @@
+use crate::{tickflow::StringPool, BTKS};
 use bytestream::{ByteOrder::LittleEndian as LE, StreamWriter};
 use std::{
     fs::File,
-    io::Write,
+    io::{Cursor, Write},
     path::{Path, PathBuf},
 };
 use tickflow_parse::old::{parse_from_text, CommandName, Context, ParsedStatement, ParsedValue};
 
+pub mod blocks;
 pub mod commands;
+pub mod expr;
+pub mod layout;
+pub mod link;
+pub mod listing;
+pub mod object;
+pub mod preprocess;
+pub mod symbols;
+pub mod validate;
 
 pub enum CompiledFileType {
     Tickompiler,
     BTKS,
+    /// A relocatable module, to be linked with others by [`link`]
+    Object,
+}
+
+/// Files to write alongside the compiled output
+#[derive(Debug, Clone, Default)]
+pub struct ExtraOutputs {
+    /// Where to write a listing of every command's offset, encoded words and source line, as
+    /// described in [`listing`]
+    pub listing: Option<PathBuf>,
+    /// Where to write a CSV file of every label's offset and source location
+    pub symbols: Option<PathBuf>,
+    /// Where the compiled tickflow is packed in a C00.bin, to add absolute addresses to the
+    /// symbol file
+    pub c00: Option<symbols::C00Placement>,
 }
 
 pub fn compile_file(
     in_: impl AsRef<Path>,
     out: impl AsRef<Path>,
     out_filetype: CompiledFileType,
+) -> tickflow_parse::Result<()> {
+    compile_file_with_preprocessor(
+        in_,
+        out,
+        out_filetype,
+        preprocess::Preprocessor::new(),
+        &ExtraOutputs::default(),
+    )
+}
+
+/// Compiles a file with a preprocessor that's already been set up, such as with include folders
+/// or a name map, and writes any extra outputs that are asked for
+pub fn compile_file_with_preprocessor(
+    in_: impl AsRef<Path>,
+    out: impl AsRef<Path>,
+    out_filetype: CompiledFileType,
+    mut preprocessor: preprocess::Preprocessor,
+    extra: &ExtraOutputs,
 ) -> tickflow_parse::Result<()> {
     let cwd = in_.as_ref().parent().ok_or(std::io::Error::new(
         std::io::ErrorKind::Other,
@@ -28,8 +71,9 @@ pub fn compile_file(
         .map(|c| c.to_str())
         .unwrap_or(None)
         .unwrap_or("");
+    let text = blocks::allocate_label_ids(&preprocessor.process_file(&in_)?);
     let cmds = Context::parse_file(
-        parse_from_text(fname, &mut File::open(&in_)?)?,
+        parse_from_text(fname, &mut text.as_bytes())?,
         |c| {
             let mut cwd = PathBuf::from(cwd);
             cwd.push(c);
@@ -37,16 +81,55 @@ pub fn compile_file(
         },
         fname,
     )?;
-    match out_filetype {
-        CompiledFileType::Tickompiler => to_btkm(File::create(out)?, cmds).map_err(Into::into),
-        CompiledFileType::BTKS => to_btks(File::create(out)?, cmds).map_err(Into::into),
+    let mut listing = extra
+        .listing
+        .as_ref()
+        .map(File::create)
+        .transpose()?
+        .map(listing::Listing::new);
+    let symbols = match out_filetype {
+        CompiledFileType::Tickompiler => to_btkm(
+            File::create(out)?,
+            cmds,
+            &preprocessor,
+            listing.as_mut(),
+            None,
+        )?,
+        CompiledFileType::BTKS => {
+            to_btks(File::create(out)?, cmds, &preprocessor, listing.as_mut())?
+        }
+        CompiledFileType::Object => {
+            to_object(File::create(out)?, cmds, &preprocessor, listing.as_mut())?
+        }
+    };
+    if let Some(path) = &extra.symbols {
+        symbols::write_csv(&symbols, extra.c00.as_ref(), &mut File::create(path)?)?;
     }
+    Ok(())
 }
 
-fn to_btkm(mut out: File, cmds: Context) -> std::io::Result<()> {
-    let mut cmd_size = 0;
+/// Compiles to a Tickompiler .bin, or to the .bin of an object module if `relocations` is given,
+/// in which case labels from other modules get added to it
+fn to_btkm<W: Write, L: Write>(
+    mut out: W,
+    cmds: Context,
+    preprocessor: &preprocess::Preprocessor,
+    mut listing: Option<&mut listing::Listing<L>>,
+    mut relocations: Option<&mut Vec<object::Relocation>>,
+) -> std::io::Result<Vec<symbols::Symbol>> {
+    let exprs = &preprocessor.exprs;
     let mut resolved_cmds = vec![];
+    // the source line each statement starts, if it's the first one from that line
+    let mut locations = vec![];
+    let mut location = None;
     for cmd in cmds.parsed_cmds {
+        if let ParsedStatement::Label(name, _) = &cmd {
+            if let Some(c) = preprocess::location_index(name) {
+                location = preprocessor.locations.get(c);
+                continue;
+            }
+        }
+        locations.push(location.take());
         let ParsedStatement::Command { cmd, arg0, args } = cmd else {
             resolved_cmds.push(cmd);
             continue;
@@ -63,20 +146,6 @@ fn to_btkm(mut out: File, cmds: Context) -> std::io::Result<()> {
             CommandName::Named(c) if *c == "int" => (0xFFFF, 1, args.clone()),
             CommandName::Named(c) => commands::resolve_command(&c, arg0, args.clone())?,
         };
-        if cmd != 0xFFFF {
-            cmd_size += 4 * (1 + args.len());
-        } else if arg0 == 0 {
-            cmd_size += args.len()
-                + if args.len() % 4 != 0 {
-                    4 - args.len() % 4
-                } else {
-                    0
-                };
-        } else if arg0 == 1 {
-            cmd_size += args.len() * 4;
-        } else {
-            unreachable!();
-        }
         resolved_cmds.push(ParsedStatement::Command {
             cmd: CommandName::Raw(cmd as i32),
             arg0: Some(arg0),
@@ -84,79 +153,130 @@ fn to_btkm(mut out: File, cmds: Context) -> std::io::Result<()> {
         });
     }
 
+    let mut diagnostics = validate::check_labels(&resolved_cmds, exprs, relocations.is_some());
+    diagnostics.extend(validate::check_control_flow(&resolved_cmds));
+    validate::report(diagnostics)?;
+    let layout = layout::Layout::new(&resolved_cmds);
+
     // "header"
+    // a module doesn't need to have the start or assets, as long as another module does
+    let header_label = |name: &str| match layout.label(name) {
+        Some(c) => Ok(c),
+        None if relocations.is_some() => Ok(-1),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("Could not find label {name}"),
+        )),
+    };
+    let start = cmds.start[0].map_or_else(|| header_label("start"), Ok)?;
+    let assets = cmds.start[1].map_or_else(|| header_label("assets"), Ok)?;
     cmds.index.write_to(&mut out, LE)?;
-    cmds.start[0]
-        .unwrap_or_else(|| get_pos_of_label(&resolved_cmds, "start").unwrap())
-        .write_to(&mut out, LE)?;
-    cmds.start[1]
-        .unwrap_or_else(|| get_pos_of_label(&resolved_cmds, "assets").unwrap())
-        .write_to(&mut out, LE)?;
+    start.write_to(&mut out, LE)?;
+    assets.write_to(&mut out, LE)?;
+    // with #start/#assets the header points at an offset rather than at a label
+    let kind = |name: &str, offset: usize| match (cmds.start, name) {
+        ([None, _], "start") => symbols::SymbolKind::Start,
+        ([Some(c), _], _) if c as usize == offset => symbols::SymbolKind::Start,
+        ([_, None], "assets") => symbols::SymbolKind::Assets,
+        ([_, Some(c)], _) if c as usize == offset => symbols::SymbolKind::Assets,
+        _ => symbols::SymbolKind::Label,
+    };
 
-    let cmds = resolved_cmds
-        .iter()
-        .filter(|c| matches!(c, ParsedStatement::Command { .. }));
-    let mut str_data = vec![];
+    let mut str_data = StringPool::new();
+    let mut offset = 0;
+    let mut symbols = vec![];
+    // the last line that had anything on it, which is where statements that don't have a line
+    // of their own (like labels in macros) came from
+    let mut last_location = None;
 
-    for cmd in cmds {
-        let ParsedStatement::Command {
-            cmd: CommandName::Raw(cmd),
-            arg0: Some(arg0),
-            args,
-        } = cmd
-        else {
-            unreachable!();
+    for (cmd, location) in resolved_cmds.iter().zip(locations) {
+        last_location = location.or(last_location);
+        let (cmd, arg0, args) = match cmd {
+            ParsedStatement::Label(name, _) => {
+                if let Some(listing) = listing.as_mut() {
+                    listing.label(offset, name, location)?;
+                }
+                symbols.push(symbols::Symbol {
+                    name: name.clone(),
+                    offset: offset as u32,
+                    location: last_location.cloned(),
+                    kind: kind(name, offset),
+                });
+                continue;
+            }
+            ParsedStatement::Command {
+                cmd: CommandName::Raw(cmd),
+                arg0: Some(arg0),
+                args,
+            } => (*cmd as u16, *arg0, args),
+            _ => continue,
         };
-
-        let cmd = *cmd as u16;
-
-        if args.len() > 15 {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "too many arguments given to a command",
-            ))?
-        }
+        let size = layout::command_size(cmd, arg0, args);
 
         if cmd == 0xFFFF {
-            (-1i32).write_to(&mut out, LE)?;
-            1.write_to(&mut out, LE)?;
-            match arg0 {
-                0 => {
-                    let ann = 3 + ((args.len() as u32) << 8);
-                    ann.write_to(&mut out, LE)?;
-                    for arg in args {
-                        let ParsedValue::Integer(arg) = arg else {
-                            Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                "bytes args must be ints",
-                            ))?
+            let mut data = vec![];
+            let kind = if arg0 == 0 { "bytes" } else { "int" };
+            for arg in args {
+                let arg = match arg {
+                    ParsedValue::Integer(c) => *c,
+                    // expressions with labels in them are fine as long as they work out to a
+                    // plain number, like `end - start`, as data has no pointer annotations
+                    ParsedValue::Label(lab) => {
+                        let value = match expr::placeholder_index(lab) {
+                            Some(c) => {
+                                exprs[c].eval(&|c| layout.label(c).map(expr::Value::pointer))?
+                            }
+                            None => expr::Value::pointer(0),
                         };
-                        (*arg as u8).write_to(&mut out, LE)?;
-                    }
-                    if args.len() % 4 != 0 {
-                        for _ in 0..(4 - (args.len() % 4)) {
-                            (0u8).write_to(&mut out, LE)?;
-                        }
-                    }
-                }
-                1 => {
-                    let ann = 3 + ((args.len() as u32 * 4) << 8);
-                    ann.write_to(&mut out, LE)?;
-                    for arg in args {
-                        let ParsedValue::Integer(arg) = arg else {
+                        if value.is_pointer() {
                             Err(std::io::Error::new(
                                 std::io::ErrorKind::Other,
-                                "int args must be ints",
+                                format!("{kind} args can't be pointers to labels"),
                             ))?
-                        };
-                        arg.write_to(&mut out, LE)?;
+                        }
+                        value.value
                     }
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("{kind} args must be ints"),
+                    ))?,
+                };
+                match arg0 {
+                    0 => data.push(arg as u8),
+                    1 => data.extend(arg.to_le_bytes()),
+                    _ => unreachable!(),
                 }
-                _ => unreachable!(),
             }
+            // the size has to fit where an annotation's argument index goes
+            if data.len() > 0xFFFF {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "data block is {:#X} bytes long, but can be at most 0xFFFF",
+                        data.len()
+                    ),
+                ))?
+            }
+            (-1i32).write_to(&mut out, LE)?;
+            1.write_to(&mut out, LE)?;
+            let ann = 3 + ((data.len() as u32) << 8);
+            ann.write_to(&mut out, LE)?;
+            out.write_all(&data)?;
+            out.write_all(&vec![0; size - data.len()])?;
+            if let Some(listing) = listing.as_mut() {
+                listing.data(offset, &data, location)?;
+            }
+            offset += size;
             continue;
         }
 
+        if args.len() > 15 {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "too many arguments given to a command",
+            ))?
+        }
+
         let op_int = (cmd & 0x3FF) as u32 + ((args.len() & 0xF) << 10) as u32 + (arg0 << 14);
         let mut parsed_args = vec![];
         let mut arg_anns: Vec<u32> = vec![];
@@ -164,30 +284,38 @@ fn to_btkm(mut out: File, cmds: Context) -> std::io::Result<()> {
             match arg {
                 tickflow_parse::old::ParsedValue::Integer(c) => parsed_args.push(*c),
                 tickflow_parse::old::ParsedValue::Label(lab) => {
-                    arg_anns.push((i as u32) << 8);
-                    parsed_args.push(get_pos_of_label(&resolved_cmds, lab).ok_or(
-                        std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!("Could not find label {lab}"),
-                        ),
-                    )?)
+                    let external = match relocations.as_mut() {
+                        Some(relocations) => {
+                            object::external_label(lab, exprs, &layout)?.map(|(label, value)| {
+                                relocations.push(object::Relocation {
+                                    offset: offset as u32,
+                                    arg: i as u32,
+                                    label,
+                                });
+                                expr::Value::pointer(value)
+                            })
+                        }
+                        None => None,
+                    };
+                    let value = match expr::placeholder_index(lab) {
+                        _ if external.is_some() => external.unwrap(),
+                        Some(c) => exprs[c].eval(&|c| layout.label(c).map(expr::Value::pointer))?,
+                        None => layout.label(lab).map(expr::Value::pointer).ok_or(
+                            std::io::Error::new(
+                                std::io::ErrorKind::Other,
+                                format!("Could not find label {}", preprocess::display_label(lab)),
+                            ),
+                        )?,
+                    };
+                    if value.is_pointer() {
+                        arg_anns.push((i as u32) << 8);
+                    }
+                    parsed_args.push(value.value)
                 }
                 tickflow_parse::old::ParsedValue::String { value, is_unicode } => {
                     arg_anns.push(((i as u32) << 8) + if *is_unicode { 1 } else { 2 });
-                    parsed_args.push((cmd_size + str_data.len()) as i32);
-                    let binary_data = if *is_unicode {
-                        let mut out = vec![];
-                        for i in value.encode_utf16() {
-                            out.extend(i.to_le_bytes())
-                        }
-                        out.extend(vec![0; if out.len() % 4 == 2 { 2 } else { 4 }]);
-                        out
-                    } else {
-                        let mut out = value.bytes().collect::<Vec<_>>();
-                        out.extend(vec![0; 4 - (out.len() % 4)]);
-                        out
-                    };
-                    str_data.extend(binary_data);
+                    parsed_args
+                        .push((layout.size + str_data.add(value, *is_unicode) as usize) as i32);
                 }
             }
         }
@@ -199,50 +327,65 @@ fn to_btkm(mut out: File, cmds: Context) -> std::io::Result<()> {
             }
         }
         op_int.write_to(&mut out, LE)?;
-        for arg in parsed_args {
+        for arg in &parsed_args {
             arg.write_to(&mut out, LE)?;
         }
+        if let Some(listing) = listing.as_mut() {
+            let words = [op_int]
+                .into_iter()
+                .chain(parsed_args.iter().map(|c| *c as u32))
+                .collect::<Vec<_>>();
+            listing.command(offset, &words, location)?;
+        }
+        offset += size;
     }
     (-2i32).write_to(&mut out, LE)?;
-    out.write_all(&str_data)?;
-    Ok(())
+    out.write_all(str_data.data())?;
+    if let Some(listing) = listing {
+        listing.strings(layout.size, &str_data)?;
+    }
+    Ok(symbols)
 }
 
-fn to_btks(mut out: File, cmds: Context) -> std::io::Result<()> {
-    todo!()
+fn to_btks<L: Write>(
+    mut out: File,
+    cmds: Context,
+    preprocessor: &preprocess::Preprocessor,
+    listing: Option<&mut listing::Listing<L>>,
+) -> std::io::Result<Vec<symbols::Symbol>> {
+    // compiled as a .bin first, and then converted like any other Tickompiler binary
+    let mut bin = Cursor::new(vec![]);
+    let symbols = to_btkm(&mut bin, cmds, preprocessor, listing, None)?;
+    let size = bin.get_ref().len() as u64;
+    BTKS::from_tickompiler_binary(&mut bin, size, vec![], false)?.to_btks_file(&mut out)?;
+    Ok(symbols)
 }
 
-fn get_pos_of_label(cmds: &[ParsedStatement], name: &str) -> Option<i32> {
-    let mut cumulative_len = 0;
-    for statement in cmds {
-        match statement {
-            ParsedStatement::Label(c, _) => {
-                if c == name {
-                    return Some(cumulative_len);
-                }
-            }
-            ParsedStatement::Command {
-                cmd: CommandName::Raw(cmd),
-                arg0: Some(arg0),
-                args,
-            } => {
-                if *cmd != 0xFFFF {
-                    cumulative_len += 4 * (1 + args.len() as i32);
-                } else if *arg0 == 0 {
-                    cumulative_len += args.len() as i32
-                        + if args.len() % 4 != 0 {
-                            4 - args.len() as i32 % 4
-                        } else {
-                            0
-                        };
-                } else if *arg0 == 1 {
-                    cumulative_len += args.len() as i32 * 4;
-                } else {
-                    unreachable!();
-                }
-            }
-            _ => unreachable!(),
-        }
+fn to_object<L: Write>(
+    mut out: File,
+    cmds: Context,
+    preprocessor: &preprocess::Preprocessor,
+    listing: Option<&mut listing::Listing<L>>,
+) -> std::io::Result<Vec<symbols::Symbol>> {
+    let mut bin = vec![];
+    let mut relocations = vec![];
+    let symbols = to_btkm(
+        &mut bin,
+        cmds,
+        preprocessor,
+        listing,
+        Some(&mut relocations),
+    )?;
+    let exported = symbols
+        .iter()
+        .filter(|c| preprocess::is_global_label(&c.name))
+        .map(|c| (c.name.clone(), c.offset))
+        .collect();
+    object::Object {
+        bin,
+        symbols: exported,
+        relocations,
     }
-    None
+    .write_to(&mut out)?;
+    Ok(symbols)
 }
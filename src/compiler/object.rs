@@ -0,0 +1,160 @@
+//! Relocatable object modules, which are compiled on their own and then linked together by
+//! [`super::link`]
+//!
+//! A module is a Tickompiler .bin whose pointers are relative to the start of the module, plus
+//! the global labels it defines and the labels from other modules that it uses. Its start and
+//! assets are -1 if they're not in the module.
+
+use super::{
+    expr::{self, Expr, ExprError, Value},
+    layout::Layout,
+    preprocess::is_global_label,
+};
+use bytestream::{ByteOrder, StreamReader, StreamWriter};
+use std::io::{self, Read, Write};
+
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub bin: Vec<u8>,
+    /// The global labels that other modules can use, with their offsets
+    pub symbols: Vec<(String, u32)>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// An argument that points into another module
+///
+/// The argument is annotated as a pointer and holds the number that gets added to the label's
+/// address.
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    /// Offset of the command in the module
+    pub offset: u32,
+    pub arg: u32,
+    pub label: String,
+}
+
+impl Object {
+    const MAGIC: &'static [u8; 4] = b"TKOB";
+    const REVISION: u32 = 1;
+
+    pub fn write_to<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        f.write_all(Self::MAGIC)?;
+        Self::REVISION.write_to(f, ByteOrder::LittleEndian)?;
+        (self.bin.len() as u32).write_to(f, ByteOrder::LittleEndian)?;
+        f.write_all(&self.bin)?;
+        (self.symbols.len() as u32).write_to(f, ByteOrder::LittleEndian)?;
+        for (name, offset) in &self.symbols {
+            offset.write_to(f, ByteOrder::LittleEndian)?;
+            write_name(f, name)?;
+        }
+        (self.relocations.len() as u32).write_to(f, ByteOrder::LittleEndian)?;
+        for relocation in &self.relocations {
+            relocation.offset.write_to(f, ByteOrder::LittleEndian)?;
+            relocation.arg.write_to(f, ByteOrder::LittleEndian)?;
+            write_name(f, &relocation.label)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(f: &mut R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        f.read_exact(&mut magic)?;
+        if &magic != Self::MAGIC {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an object module",
+            ))?
+        }
+        let revision = u32::read_from(f, ByteOrder::LittleEndian)?;
+        if revision != Self::REVISION {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "object module is revision {revision}, but only revision {} is supported",
+                    Self::REVISION
+                ),
+            ))?
+        }
+        let size = u32::read_from(f, ByteOrder::LittleEndian)?;
+        let mut bin = vec![0; size as usize];
+        f.read_exact(&mut bin)?;
+        let mut symbols = vec![];
+        for _ in 0..u32::read_from(f, ByteOrder::LittleEndian)? {
+            let offset = u32::read_from(f, ByteOrder::LittleEndian)?;
+            symbols.push((read_name(f)?, offset));
+        }
+        let mut relocations = vec![];
+        for _ in 0..u32::read_from(f, ByteOrder::LittleEndian)? {
+            relocations.push(Relocation {
+                offset: u32::read_from(f, ByteOrder::LittleEndian)?,
+                arg: u32::read_from(f, ByteOrder::LittleEndian)?,
+                label: read_name(f)?,
+            });
+        }
+        Ok(Self {
+            bin,
+            symbols,
+            relocations,
+        })
+    }
+}
+
+fn write_name<W: Write>(f: &mut W, name: &str) -> io::Result<()> {
+    (name.len() as u32).write_to(f, ByteOrder::LittleEndian)?;
+    f.write_all(name.as_bytes())
+}
+
+fn read_name<R: Read>(f: &mut R) -> io::Result<String> {
+    let len = u32::read_from(f, ByteOrder::LittleEndian)?;
+    let mut name = vec![0; len as usize];
+    f.read_exact(&mut name)?;
+    String::from_utf8(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Works out a label argument that uses a label from another module, which is only allowed if
+/// the argument is an offset from that label
+///
+/// Gives the label and the number to add to its address, or `None` if all the labels are in
+/// this module.
+pub fn external_label(
+    arg: &str,
+    exprs: &[Expr],
+    layout: &Layout,
+) -> io::Result<Option<(String, i32)>> {
+    let expr = match expr::placeholder_index(arg) {
+        Some(c) => exprs[c].clone(),
+        None => Expr::Name(arg.to_string()),
+    };
+    let names = expr.names();
+    let mut external = names.iter().filter(|c| layout.label(c).is_none());
+    let Some(&name) = external.next() else {
+        return Ok(None);
+    };
+    // local and macro labels can't be from anywhere else
+    if !is_global_label(name) {
+        Err(ExprError::Undefined(name.to_string()))?
+    }
+    if let Some(other) = external.find(|c| **c != name) {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("only one label from another module can be used in an argument, but {name} and {other} both are"),
+        ))?
+    }
+    let value = expr.eval(&|c| match c == name {
+        true => Some(Value::pointer(0)),
+        false => layout.label(c).map(Value::pointer),
+    })?;
+    // labels from this module have to cancel each other out, or the result would depend on
+    // where the module ends up
+    let only_external = expr.eval(&|c| match c == name {
+        true => Some(Value::pointer(0)),
+        false => layout.label(c).map(Value::int),
+    })?;
+    if !value.is_pointer() || !only_external.is_pointer() {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("label {name} is from another module, so it can only be used as a pointer with an offset, like '{name} + 4'"),
+        ))?
+    }
+    Ok(Some((name.to_string(), value.value)))
+}
@@ -0,0 +1,762 @@
+//! Text pass that runs over tickflow source before it's handed to the parser
+
+use super::{
+    blocks::{BlockLine, Blocks},
+    commands::TICKOMPILER_COMMANDS,
+    expr::{self, Expr, ExprError},
+};
+use crate::c00::NameResolver;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Rewrites source so the tickflow parser can read it
+///
+/// Constant expressions in arguments and arg0s get replaced by their value. Expressions that use
+/// labels can only be worked out after layout, so they're kept in `exprs` and replaced by a
+/// placeholder label (see [`expr::placeholder`]).
+///
+/// It also takes care of `#define NAME value` constants and `#include`s, so included headers can
+/// define constants too, and of built-in game and tempo names, like `game.rvlRap` and
+/// `tempo.STRM_TITLE` (see [`NameResolver`]). `#include "file"` looks next to the including file
+/// first, then in `include_dirs` and then in `library`, while `#include <file>` skips the
+/// including file's folder. A file with `#once` in it is only ever included once, and any
+/// `#include`s of it after that are skipped.
+///
+/// `#incbin "file"` pulls a whole file in as a `bytes` data block, and `#incbin "file", offset,
+/// length` only a part of it. Put a label before it to point at the data.
+///
+/// Macros are defined between `#macro name param1, param2` and `#endmacro`, and used like any
+/// other command. Labels defined inside a macro get a unique name for every use of it.
+///
+/// Labels starting with a dot, like `.loop`, are local to the last global label before them, and
+/// get renamed with [`local_label`]. Labels starting with `__` are kept for the ones the
+/// preprocessor makes up, so they can't be defined in source.
+///
+/// Block syntax for conditionals and loops is lowered as described in [`super::blocks`].
+///
+/// Every source line with code on it is preceded in the output by a marker label (see
+/// [`location_index`]) that points into `locations`, so the compiler can tell which line each
+/// command came from.
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessor {
+    pub constants: HashMap<String, i32>,
+    /// Names for `#index`, `game.NAME` and `tempo.NAME`
+    pub names: NameResolver,
+    pub exprs: Vec<Expr>,
+    pub macros: HashMap<String, Macro>,
+    /// The macro that's being defined, if any
+    recording: Option<(String, Macro)>,
+    expansions: usize,
+    depth: usize,
+    /// The global label that local labels are currently under
+    scope: Option<String>,
+    blocks: Blocks,
+    /// Folders to look for included files in
+    pub include_dirs: Vec<PathBuf>,
+    /// A project's shared library of subs, looked in after `include_dirs`
+    pub library: Option<PathBuf>,
+    /// Files that are being preprocessed, from the first to the latest include, used to report
+    /// include cycles
+    include_stack: Vec<PathBuf>,
+    /// Files that have `#once` in them
+    once: HashSet<PathBuf>,
+    /// Where each line with code on it came from
+    pub locations: Vec<SourceLocation>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Macro {
+    pub params: Vec<String>,
+    pub body: Vec<String>,
+}
+
+/// A line of source, for the commands that came from it
+#[derive(Debug, Clone)]
+pub struct SourceLocation {
+    pub file: String,
+    /// Line number, starting from 1
+    pub line: usize,
+    /// The line as it was written, without indentation
+    pub text: String,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// How deep macros can be used inside other macros, to catch macros that use themselves
+const MAX_MACRO_DEPTH: usize = 64;
+
+const LOCAL_PREFIX: &str = "__local_";
+const MACRO_PREFIX: &str = "__macro_";
+const LOCATION_PREFIX: &str = "__loc_";
+
+/// Gets the index into [`Preprocessor::locations`] out of a location marker label
+pub fn location_index(label: &str) -> Option<usize> {
+    label.strip_prefix(LOCATION_PREFIX)?.parse().ok()
+}
+
+/// Name that a local label gets once it's been given its scope
+///
+/// The scope's length goes in the name too, so it can be split back apart whatever the two
+/// names have in them.
+pub fn local_label(scope: &str, local: &str) -> String {
+    format!("{LOCAL_PREFIX}{}_{scope}_{local}", scope.len())
+}
+
+/// Name that a label defined in a macro gets for one use of that macro
+pub fn macro_label(name: &str, expansion: usize, label: &str) -> String {
+    format!("{MACRO_PREFIX}{}_{name}_{expansion}_{label}", name.len())
+}
+
+/// Splits `{len}_{first}_{rest}` back into its two names
+fn split_mangled(name: &str) -> Option<(&str, &str)> {
+    let (len, rest) = name.split_once('_')?;
+    let len = len.parse::<usize>().ok()?;
+    Some((rest.get(..len)?, rest.get(len + 1..)?))
+}
+
+/// Whether a label starts a new function, rather than being a local or macro label that the
+/// preprocessor made (all of which start with `__`)
+pub fn is_global_label(label: &str) -> bool {
+    !label.starts_with("__")
+}
+
+/// Name to show for a label in messages, which for local labels is `scope.local` and for macro
+/// labels `macro#use.label`
+pub fn display_label(label: &str) -> Cow<'_, str> {
+    if let Some((scope, local)) = label.strip_prefix(LOCAL_PREFIX).and_then(split_mangled) {
+        return Cow::Owned(format!("{scope}.{local}"));
+    }
+    let in_macro = label
+        .strip_prefix(MACRO_PREFIX)
+        .and_then(split_mangled)
+        .and_then(|(name, rest)| Some((name, rest.split_once('_')?)));
+    match in_macro {
+        Some((name, (expansion, label))) => Cow::Owned(format!("{name}#{expansion}.{label}")),
+        None => Cow::Borrowed(label),
+    }
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads and preprocesses a file, with its includes relative to its folder
+    pub fn process_file(&mut self, path: impl AsRef<Path>) -> io::Result<String> {
+        let path = path.as_ref();
+        let canonical = path.canonicalize()?;
+        if let Some(pos) = self.include_stack.iter().position(|c| *c == canonical) {
+            let chain = self.include_stack[pos..]
+                .iter()
+                .chain([&canonical])
+                .map(|c| c.display().to_string())
+                .collect::<Vec<_>>();
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("include cycle: {}", chain.join(" -> ")),
+            ))?
+        }
+        if self.once.contains(&canonical) {
+            return Ok(String::new());
+        }
+
+        let text = fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let fname = path.file_name().and_then(|c| c.to_str()).unwrap_or("");
+        self.include_stack.push(canonical);
+        let out = self.process(&text, dir, fname);
+        self.include_stack.pop();
+        out
+    }
+
+    /// Finds the file an `#include` points to
+    fn find_include(&self, name: &str, dir: &Path) -> io::Result<PathBuf> {
+        let (name, local) = match name.strip_prefix('<').and_then(|c| c.strip_suffix('>')) {
+            Some(c) => (c, false),
+            None => (name.trim_matches('"'), true),
+        };
+        let local = local.then_some(dir);
+        let dirs = local
+            .into_iter()
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .chain(self.library.as_deref())
+            .collect::<Vec<_>>();
+        for dir in &dirs {
+            let path = dir.join(name);
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+        let looked_in = if dirs.is_empty() {
+            "no include folders were given".to_string()
+        } else {
+            let dirs = dirs
+                .iter()
+                .map(|c| format!("'{}'", c.display()))
+                .collect::<Vec<_>>();
+            format!("looked in {}", dirs.join(", "))
+        };
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("couldn't find {name} to include ({looked_in})"),
+        ))
+    }
+
+    pub fn process(&mut self, text: &str, dir: &Path, fname: &str) -> io::Result<String> {
+        let mut out = String::with_capacity(text.len());
+        let depth = self.blocks.depth();
+        for (i, source) in text.lines().enumerate() {
+            let line = self
+                .check_label(source)
+                .and_then(|_| self.process_line(source, dir))
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("{fname}, line {}: {e}", i + 1),
+                    )
+                })?;
+            let code = line
+                .lines()
+                .map(|c| split_comment(c).0.trim())
+                .find(|c| !c.is_empty());
+            // directives aren't commands, and included files have markers of their own
+            if matches!(code, Some(c) if !c.starts_with('#') && !c.starts_with(LOCATION_PREFIX)) {
+                out.push_str(&format!("{LOCATION_PREFIX}{}:\n", self.locations.len()));
+                self.locations.push(SourceLocation {
+                    file: fname.to_string(),
+                    line: i + 1,
+                    text: source.trim().to_string(),
+                });
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+        if let Some((name, _)) = self.recording.take() {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{fname}: macro {name} is never closed with #endmacro"),
+            ))?
+        }
+        if self.blocks.depth() != depth {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{fname}: a block is never closed with '}}'"),
+            ))?
+        }
+        Ok(out)
+    }
+
+    /// Makes sure a line from a file doesn't define a label with a name the preprocessor uses
+    fn check_label(&self, line: &str) -> io::Result<()> {
+        match split_comment(line).0.trim().strip_suffix(':') {
+            Some(label) if !is_global_label(label.trim()) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "label {} starts with __, which is kept for labels the preprocessor makes",
+                    label.trim()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn process_line(&mut self, line: &str, dir: &Path) -> io::Result<String> {
+        let (code, comment) = split_comment(line);
+        let trimmed = code.trim();
+        if let Some((_, mac)) = &mut self.recording {
+            if trimmed == "#endmacro" {
+                let (name, mac) = self.recording.take().unwrap();
+                self.macros.insert(name, mac);
+            } else {
+                mac.body.push(line.to_string());
+            }
+            return Ok(String::new());
+        }
+        if let Some(directive) = trimmed.strip_prefix('#') {
+            return self
+                .directive(directive, dir)
+                .map(|c| c.unwrap_or(line.to_string()));
+        }
+        if let Some(label) = trimmed.strip_suffix(':') {
+            let label = label.trim();
+            if let Some(local) = label.strip_prefix('.') {
+                let scope = self.scope.as_ref().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("local label {label} has no global label before it"),
+                    )
+                })?;
+                return Ok(format!("{}:{comment}", local_label(scope, local)));
+            }
+            // labels from macros and blocks don't start a new scope
+            if self.depth == 0 && is_global_label(label) {
+                self.scope = Some(label.to_string());
+            }
+            return Ok(line.to_string());
+        }
+        if trimmed.is_empty() {
+            return Ok(line.to_string());
+        }
+        if let Some(block) = BlockLine::parse(trimmed) {
+            let lines = match block {
+                BlockLine::Open(c) => self.blocks.open(c)?,
+                BlockLine::Else => self.blocks.else_branch()?,
+                BlockLine::Close => self.blocks.close()?,
+            };
+            let mut out = vec![];
+            for line in lines {
+                out.push(self.process_line(&line, dir)?);
+            }
+            return Ok(out.join("\n"));
+        }
+
+        let indent = &code[..code.len() - code.trim_start().len()];
+        let qualified = self.qualify_locals(trimmed)?;
+        let (old_head, old_args) = split_head(&qualified);
+        let old_args = split_args(old_args);
+        if self.macros.contains_key(old_head) {
+            return self.expand(old_head, &old_args, dir);
+        }
+        let head = match old_head.split_once('<') {
+            Some((name, arg0)) => {
+                let arg0 = arg0.strip_suffix('>').unwrap_or(arg0);
+                format!("{name}<{}>", self.arg0(arg0)?)
+            }
+            None => old_head.to_string(),
+        };
+        let args = old_args
+            .iter()
+            .map(|c| self.arg(c))
+            .collect::<Result<Vec<_>, _>>()?;
+        if qualified == trimmed
+            && head == old_head
+            && args.iter().zip(&old_args).all(|(a, b)| a == b.trim())
+        {
+            return Ok(line.to_string());
+        }
+
+        let mut out = format!("{indent}{head}");
+        if !args.is_empty() {
+            out.push(' ');
+            out.push_str(&args.join(", "));
+        }
+        out.push_str(comment);
+        Ok(out)
+    }
+
+    /// Handles the directives that the preprocessor takes care of, and leaves the rest alone by
+    /// returning `None`
+    fn directive(&mut self, directive: &str, dir: &Path) -> io::Result<Option<String>> {
+        let (name, value) = directive
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive, ""));
+        let value = value.trim();
+        match name {
+            "define" => {
+                let (name, value) = value.split_once(char::is_whitespace).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Other, "#define needs a name and a value")
+                })?;
+                if !is_identifier(name) {
+                    Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("invalid constant name {name}"),
+                    ))?
+                }
+                let value = self.resolve(Expr::parse(value)?)?.eval(&|_| None)?.value;
+                match self.constants.insert(name.to_string(), value) {
+                    Some(old) if old != value => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("constant {name} is already defined as {old}"),
+                    ))?,
+                    _ => Ok(Some(String::new())),
+                }
+            }
+            "include" => {
+                let path = self.find_include(value, dir)?;
+                // local labels in the included file can't belong to the including file's
+                // labels, or the other way around
+                let scope = self.scope.take();
+                let text = self.process_file(path);
+                self.scope = scope;
+                // the last line break gets added back by the including file
+                let text = text?;
+                Ok(Some(text.strip_suffix('\n').unwrap_or(&text).to_string()))
+            }
+            "once" => {
+                // files that aren't read from disk can't be included anyway
+                if let Some(c) = self.include_stack.last() {
+                    self.once.insert(c.clone());
+                }
+                Ok(Some(String::new()))
+            }
+            "incbin" => {
+                let args = split_args(value);
+                let (name, range) = args.split_first().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Other, "#incbin needs a file name")
+                })?;
+                if range.len() > 2 {
+                    Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "#incbin takes a file name, an offset and a length",
+                    ))?
+                }
+                let path = self.find_include(name.trim(), dir)?;
+                let data = fs::read(&path)?;
+                let mut range = range
+                    .iter()
+                    .map(|c| Ok(self.resolve(Expr::parse(c)?)?.eval(&|_| None)?.value as usize))
+                    .collect::<io::Result<Vec<_>>>()?
+                    .into_iter();
+                let start = range.next().unwrap_or(0);
+                let end = match range.next() {
+                    Some(c) => start.saturating_add(c),
+                    None => data.len().max(start),
+                };
+                let data = data.get(start..end).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "can't include bytes {start:#X} to {end:#X} of {}, which is only {:#X} bytes long",
+                            path.display(),
+                            data.len()
+                        ),
+                    )
+                })?;
+                if data.is_empty() {
+                    Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("no data to include from {}", path.display()),
+                    ))?
+                }
+                let bytes = data.iter().map(|c| format!("{c:#X}")).collect::<Vec<_>>();
+                Ok(Some(format!("bytes {}", bytes.join(", "))))
+            }
+            "macro" => {
+                let (name, params) = split_head(value);
+                if !is_identifier(name) {
+                    Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("invalid macro name {name}"),
+                    ))?
+                }
+                if TICKOMPILER_COMMANDS.iter().any(|c| c.0 == name) {
+                    Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("macro {name} has the same name as a command"),
+                    ))?
+                }
+                let params = split_args(params)
+                    .into_iter()
+                    .map(|c| c.trim().to_string())
+                    .collect::<Vec<_>>();
+                if let Some(c) = params.iter().find(|c| !is_identifier(c)) {
+                    Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("invalid parameter name {c} for macro {name}"),
+                    ))?
+                }
+                self.recording = Some((
+                    name.to_string(),
+                    Macro {
+                        params,
+                        body: vec![],
+                    },
+                ));
+                Ok(Some(String::new()))
+            }
+            "endmacro" => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "#endmacro without a matching #macro",
+            ))?,
+            // the game index can also be given by name, without a `game.` prefix
+            "index" => {
+                let expr = match Expr::parse(value)? {
+                    Expr::Name(c) if !c.contains('.') && !self.constants.contains_key(&c) => {
+                        Expr::Num(self.names.game_index(&c)? as i32)
+                    }
+                    c => c,
+                };
+                let value = self.resolve(expr)?.eval(&|_| None)?.value;
+                Ok(Some(format!("#index {:#X}", value)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Writes out a use of a macro, with its parameters and labels replaced
+    fn expand(&mut self, name: &str, args: &[&str], dir: &Path) -> io::Result<String> {
+        let mac = self.macros[name].clone();
+        if args.len() != mac.params.len() {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "macro {name} takes {} arguments, but {} were given",
+                    mac.params.len(),
+                    args.len()
+                ),
+            ))?
+        }
+        if self.depth >= MAX_MACRO_DEPTH {
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("macro {name} is nested too deep, does it use itself?"),
+            ))?
+        }
+        self.expansions += 1;
+
+        let mut values = HashMap::new();
+        for (param, arg) in mac.params.iter().zip(args) {
+            let arg = arg.trim();
+            let is_simple = arg.starts_with('"')
+                || arg.starts_with("u\"")
+                || arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+            // keep expressions together when they're used inside other expressions
+            let value = if is_simple {
+                arg.to_string()
+            } else {
+                format!("({arg})")
+            };
+            values.insert(param.as_str(), value);
+        }
+        for line in &mac.body {
+            let code = split_comment(line).0.trim();
+            if let Some(label) = code.strip_suffix(':') {
+                let label = label.trim();
+                values.insert(
+                    label,
+                    macro_label(name, self.expansions, label.trim_start_matches('.')),
+                );
+            }
+        }
+
+        self.depth += 1;
+        let mut out = vec![];
+        for line in &mac.body {
+            let code = split_comment(line).0.trim();
+            let line = if let Some(label) = code.strip_suffix(':') {
+                format!("{}:", values[label.trim()])
+            } else {
+                replace_identifiers(code, !code.starts_with('#'), |c| values.get(c).cloned())
+            };
+            match self.process_line(&line, dir) {
+                Ok(c) => out.push(c),
+                Err(e) => {
+                    self.depth -= 1;
+                    Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("in macro {name}: {e}"),
+                    ))?
+                }
+            }
+        }
+        self.depth -= 1;
+        Ok(out.join("\n"))
+    }
+
+    /// Gives every local label used in a command its scope
+    fn qualify_locals(&self, code: &str) -> io::Result<String> {
+        let mut unscoped = None;
+        let out = replace_identifiers(code, true, |word| {
+            let local = word.strip_prefix('.')?;
+            match &self.scope {
+                Some(scope) => Some(local_label(scope, local)),
+                None => {
+                    unscoped = Some(word.to_string());
+                    None
+                }
+            }
+        });
+        match unscoped {
+            Some(c) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("local label {c} has no global label before it"),
+            )),
+            None => Ok(out),
+        }
+    }
+
+    /// Replaces named constants and built-in names (`game.NAME` and `tempo.NAME`) with their
+    /// values, leaving only labels
+    fn resolve(&self, expr: Expr) -> Result<Expr, ExprError> {
+        expr.resolve(&|name| {
+            if let Some(c) = self.constants.get(name) {
+                return Ok(Some(*c));
+            }
+            Ok(match name.split_once('.') {
+                Some(("game", c)) => Some(self.names.game_index(c)? as i32),
+                Some(("tempo", c)) => Some(self.names.tempo_id(c)? as i32),
+                _ => None,
+            })
+        })
+    }
+
+    fn arg0(&mut self, text: &str) -> Result<String, ExprError> {
+        let expr = self.resolve(Expr::parse(text)?)?;
+        if let Expr::Num(_) = expr {
+            return Ok(text.trim().to_string());
+        }
+        let value = expr.eval(&|_| None)?;
+        Ok(value.value.to_string())
+    }
+
+    fn arg(&mut self, text: &str) -> Result<String, ExprError> {
+        let text = text.trim();
+        if text.starts_with('"') || text.starts_with("u\"") {
+            return Ok(text.to_string());
+        }
+        let expr = match Expr::parse(text) {
+            Ok(c) => c,
+            // plain values are left for the parser to complain about
+            Err(_) if !text.contains(['+', '-', '*', '/', '<', '>', '|', '&', '(', ')']) => {
+                return Ok(text.to_string())
+            }
+            Err(e) => Err(e)?,
+        };
+        if let Expr::Num(_) = expr {
+            return Ok(text.to_string());
+        }
+        let expr = self.resolve(expr)?;
+        // a plain label
+        if let Expr::Name(_) = expr {
+            return Ok(text.to_string());
+        }
+
+        if expr.names().is_empty() {
+            Ok(expr.eval(&|_| None)?.value.to_string())
+        } else {
+            self.exprs.push(expr);
+            Ok(expr::placeholder(self.exprs.len() - 1))
+        }
+    }
+}
+
+/// Replaces every name in some code that `f` gives a replacement for, leaving strings and numbers
+/// alone
+///
+/// If `skip_command` is set, the first name (the command's) is never replaced.
+pub(super) fn replace_identifiers(
+    code: &str,
+    skip_command: bool,
+    mut f: impl FnMut(&str) -> Option<String>,
+) -> String {
+    let mut out = String::with_capacity(code.len());
+    let mut chars = code.char_indices().peekable();
+    let mut in_string = false;
+    let mut first = true;
+    while let Some((i, c)) = chars.next() {
+        if in_string || c == '"' {
+            if c == '"' {
+                in_string = !in_string;
+            } else if c == '\\' {
+                out.push(c);
+                if let Some((_, c)) = chars.next() {
+                    out.push(c);
+                }
+                continue;
+            }
+            out.push(c);
+            continue;
+        }
+        if !(c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+            out.push(c);
+            continue;
+        }
+        let mut end = i + c.len_utf8();
+        while let Some((j, c)) = chars.peek() {
+            if !(c.is_ascii_alphanumeric() || *c == '_' || *c == '.') {
+                break;
+            }
+            end = j + c.len_utf8();
+            chars.next();
+        }
+        let word = &code[i..end];
+        let skip = c.is_ascii_digit() || (first && skip_command);
+        first = false;
+        match f(word) {
+            Some(value) if !skip => out.push_str(&value),
+            _ => out.push_str(word),
+        }
+    }
+    out
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits a line into its code and its `//` comment, ignoring any inside strings
+pub(super) fn split_comment(line: &str) -> (&str, &str) {
+    let mut in_string = false;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' if prev != '\\' => in_string = !in_string,
+            '/' if !in_string && prev == '/' => return line.split_at(i - 1),
+            _ => (),
+        }
+        prev = c;
+    }
+    (line, "")
+}
+
+/// Splits a command into its name (with arg0) and its arguments
+pub(super) fn split_head(code: &str) -> (&str, &str) {
+    let chars = code.char_indices().collect::<Vec<_>>();
+    let mut in_arg0 = false;
+    let mut depth = 0;
+    for (n, (i, c)) in chars.iter().enumerate() {
+        let next = chars.get(n + 1).map(|c| c.1);
+        let prev = n.checked_sub(1).map(|n| chars[n].1);
+        match c {
+            '<' if !in_arg0 => in_arg0 = true,
+            '(' if in_arg0 => depth += 1,
+            ')' if in_arg0 => depth -= 1,
+            // '>>' is a shift, not the end of the arg0
+            '>' if in_arg0 && depth == 0 && next != Some('>') && prev != Some('>') => {
+                in_arg0 = false
+            }
+            c if c.is_whitespace() && !in_arg0 => return (&code[..*i], code[*i..].trim()),
+            _ => (),
+        }
+    }
+    (code, "")
+}
+
+/// Splits the arguments of a command at the commas that aren't in strings or parentheses
+pub(super) fn split_args(args: &str) -> Vec<&str> {
+    if args.trim().is_empty() {
+        return vec![];
+    }
+    let mut out = vec![];
+    let mut start = 0;
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut prev = ' ';
+    for (i, c) in args.char_indices() {
+        match c {
+            '"' if prev != '\\' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                out.push(&args[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+        prev = c;
+    }
+    out.push(&args[start..]);
+    out
+}
@@ -0,0 +1,80 @@
+//! Symbol files, which map offsets in compiled tickflow back to the labels they came from
+
+use super::preprocess::{display_label, SourceLocation};
+use crate::c00::C00Type;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,
+    /// The label the header's start offset points to
+    Start,
+    /// The label the header's assets offset points to
+    Assets,
+}
+
+impl SymbolKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Label => "label",
+            Self::Start => "start",
+            Self::Assets => "assets",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    /// Offset in the .bin's tickflow, which is the same as in a BTKS's FLOW section
+    pub offset: u32,
+    pub location: Option<SourceLocation>,
+    pub kind: SymbolKind,
+}
+
+/// Where compiled tickflow gets packed in a C00.bin
+#[derive(Debug, Clone)]
+pub struct C00Placement {
+    pub c00_type: C00Type,
+    /// Position of the tickflow in the C00.bin file
+    pub position: u32,
+}
+
+impl C00Placement {
+    /// Address that an offset in the tickflow ends up at once the C00.bin is loaded
+    pub fn address(&self, offset: u32) -> u32 {
+        self.c00_type.base_offset() + self.position + offset
+    }
+}
+
+/// Writes symbols as CSV, with absolute addresses too if the tickflow's place in a C00.bin is
+/// given
+pub fn write_csv<W: Write>(
+    symbols: &[Symbol],
+    c00: Option<&C00Placement>,
+    f: &mut W,
+) -> io::Result<()> {
+    writeln!(f, "label,offset,address,file,line,kind")?;
+    for symbol in symbols {
+        writeln!(
+            f,
+            "{},{:#X},{},{},{},{}",
+            display_label(&symbol.name),
+            symbol.offset,
+            c00.map(|c| format!("{:#X}", c.address(symbol.offset)))
+                .unwrap_or_default(),
+            symbol
+                .location
+                .as_ref()
+                .map(|c| c.file.as_str())
+                .unwrap_or_default(),
+            symbol
+                .location
+                .as_ref()
+                .map(|c| c.line.to_string())
+                .unwrap_or_default(),
+            symbol.kind.name(),
+        )?;
+    }
+    Ok(())
+}
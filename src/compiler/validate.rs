@@ -0,0 +1,331 @@
+use super::{
+    commands::TICKOMPILER_COMMANDS,
+    expr::Expr,
+    preprocess::{display_label, is_global_label},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    io,
+};
+use tickflow_parse::old::{CommandName, ParsedStatement, ParsedValue};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The label the problem is under, if any
+    pub label: Option<String>,
+    /// Number of the command in its label (starting at 1), or 0 if it's about the label itself,
+    /// which the message already names
+    pub index: usize,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "Warning: ")?,
+            Severity::Error => write!(f, "Error: ")?,
+        }
+        write!(f, "{}", self.message)?;
+        match (&self.label, self.index) {
+            (_, 0) => Ok(()),
+            (Some(label), i) => write!(f, " (command #{} after label {})", i, label),
+            (None, i) => write!(f, " (command #{} at the top of the file)", i),
+        }
+    }
+}
+
+/// Prints all warnings, and fails with every error if there's any
+pub fn report(diagnostics: Vec<Diagnostic>) -> io::Result<()> {
+    let mut errors = vec![];
+    for diagnostic in diagnostics {
+        match diagnostic.severity {
+            Severity::Warning => println!("{}", diagnostic),
+            Severity::Error => errors.push(diagnostic.to_string()),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, errors.join("\n")))
+    }
+}
+
+enum Block {
+    If {
+        at: (Option<String>, usize),
+        has_else: bool,
+        /// Whether the branch before the else never gets to the endif
+        then_terminated: bool,
+    },
+    Switch {
+        at: (Option<String>, usize),
+    },
+}
+
+/// Checks the nesting of if/else/endif and switch/case/default/endswitch blocks, and looks for
+/// code that can't be reached or that can run past the end of its function
+///
+/// Takes the command stream after it's gone through [`super::commands::resolve_command`].
+pub fn check_control_flow(cmds: &[ParsedStatement]) -> Vec<Diagnostic> {
+    let mut out = vec![];
+    let mut label: Option<String> = None;
+    let mut index = 0;
+    let mut stack: Vec<Block> = vec![];
+    // whether the current position can't be reached by just running the code before it
+    let mut terminated = false;
+    let mut warned_unreachable = false;
+    let mut has_code = false;
+
+    let mut diagnostic = |severity, label: &Option<String>, index, message: &str| {
+        out.push(Diagnostic {
+            severity,
+            label: label.clone(),
+            index,
+            message: message.to_string(),
+        })
+    };
+
+    for statement in cmds {
+        let cmd = match statement {
+            ParsedStatement::Label(name, _) => {
+                // local labels are just jump targets inside the function
+                let new_function = is_global_label(name);
+                if new_function && stack.is_empty() && has_code && !terminated {
+                    diagnostic(
+                        Severity::Warning,
+                        &label,
+                        index,
+                        &format!(
+                            "code can run past the end of its function into label {}",
+                            display_label(name)
+                        ),
+                    );
+                }
+                label = Some(display_label(name).into_owned());
+                index = 0;
+                terminated = false;
+                warned_unreachable = false;
+                if new_function {
+                    has_code = false;
+                }
+                continue;
+            }
+            ParsedStatement::Command {
+                cmd: CommandName::Raw(cmd),
+                ..
+            } => *cmd,
+            _ => continue,
+        };
+        index += 1;
+
+        // data isn't code, so it can't be reached or unreached
+        if cmd == 0xFFFF {
+            continue;
+        }
+        has_code = true;
+        // label, else, endif, case, default and endswitch can all be jumped to
+        let jump_target = matches!(cmd, 0x14 | 0x17 | 0x18 | 0x1A | 0x1C | 0x1D);
+        if terminated && !warned_unreachable && !jump_target {
+            diagnostic(
+                Severity::Warning,
+                &label,
+                index,
+                "code after a return, stop, goto or break can never be reached",
+            );
+            warned_unreachable = true;
+        }
+
+        match cmd {
+            // return, stop, goto
+            0x7 | 0x8 | 0x15 => terminated = true,
+            // label
+            0x14 => terminated = false,
+            // if
+            0x16 => stack.push(Block::If {
+                at: (label.clone(), index),
+                has_else: false,
+                then_terminated: false,
+            }),
+            // else
+            0x17 => match stack.last_mut() {
+                Some(Block::If {
+                    has_else: has_else @ false,
+                    then_terminated,
+                    ..
+                }) => {
+                    *has_else = true;
+                    *then_terminated = terminated;
+                    terminated = false;
+                }
+                _ => diagnostic(Severity::Error, &label, index, "else without a matching if"),
+            },
+            // endif
+            0x18 => match stack.last() {
+                Some(Block::If {
+                    has_else,
+                    then_terminated,
+                    ..
+                }) => {
+                    terminated = *has_else && *then_terminated && terminated;
+                    stack.pop();
+                }
+                _ => diagnostic(
+                    Severity::Error,
+                    &label,
+                    index,
+                    "endif without a matching if",
+                ),
+            },
+            // switch
+            0x19 => stack.push(Block::Switch {
+                at: (label.clone(), index),
+            }),
+            // case, default
+            0x1A | 0x1C => match stack.last() {
+                Some(Block::Switch { .. }) => terminated = false,
+                _ => diagnostic(
+                    Severity::Error,
+                    &label,
+                    index,
+                    "case/default outside of a switch",
+                ),
+            },
+            // break
+            0x1B => {
+                if stack.iter().any(|c| matches!(c, Block::Switch { .. })) {
+                    terminated = true;
+                } else {
+                    diagnostic(Severity::Error, &label, index, "break outside of a switch");
+                }
+            }
+            // endswitch
+            0x1D => match stack.last() {
+                Some(Block::Switch { .. }) => {
+                    terminated = false;
+                    stack.pop();
+                }
+                _ => diagnostic(
+                    Severity::Error,
+                    &label,
+                    index,
+                    "endswitch without a matching switch",
+                ),
+            },
+            _ => (),
+        }
+        if !terminated {
+            warned_unreachable = false;
+        }
+    }
+
+    for block in stack {
+        match block {
+            Block::If { at, .. } => {
+                diagnostic(Severity::Error, &at.0, at.1, "if without a matching endif")
+            }
+            Block::Switch { at } => diagnostic(
+                Severity::Error,
+                &at.0,
+                at.1,
+                "switch without a matching endswitch",
+            ),
+        }
+    }
+    if has_code && !terminated {
+        diagnostic(
+            Severity::Warning,
+            &label,
+            index,
+            "code can run past the end of the file",
+        );
+    }
+
+    out
+}
+
+/// Looks for labels that are defined more than once, never used, or easy to mix up with another
+/// label or a command
+///
+/// In an object module, global labels can be used by other modules, so they're never unused.
+pub fn check_labels(cmds: &[ParsedStatement], exprs: &[Expr], is_module: bool) -> Vec<Diagnostic> {
+    let mut out = vec![];
+    let mut defined: Vec<&str> = vec![];
+    let mut used = exprs.iter().flat_map(Expr::names).collect::<HashSet<_>>();
+    for statement in cmds {
+        match statement {
+            ParsedStatement::Label(name, _) => defined.push(name),
+            ParsedStatement::Command { args, .. } => {
+                for arg in args {
+                    if let ParsedValue::Label(name) = arg {
+                        used.insert(name.as_str());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut diagnostic = |severity, label: &str, message: String| {
+        out.push(Diagnostic {
+            severity,
+            label: Some(display_label(label).into_owned()),
+            index: 0,
+            message,
+        })
+    };
+
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut seen_lowercase: HashMap<String, &str> = HashMap::new();
+    for name in defined {
+        let shown = display_label(name);
+        if !seen.insert(name) {
+            diagnostic(
+                Severity::Error,
+                name,
+                format!("label {shown} is defined more than once"),
+            );
+            continue;
+        }
+        match seen_lowercase.get(&name.to_lowercase()) {
+            Some(other) => diagnostic(
+                Severity::Warning,
+                name,
+                format!(
+                    "label {shown} only differs from label {} by case",
+                    display_label(other)
+                ),
+            ),
+            None => {
+                seen_lowercase.insert(name.to_lowercase(), name);
+            }
+        }
+        if TICKOMPILER_COMMANDS
+            .iter()
+            .any(|c| c.0.eq_ignore_ascii_case(name))
+        {
+            diagnostic(
+                Severity::Warning,
+                name,
+                format!("label {shown} has the same name as a command"),
+            );
+        }
+        let exported = is_module && is_global_label(name);
+        if name != "start" && name != "assets" && !exported && !used.contains(name) {
+            diagnostic(
+                Severity::Warning,
+                name,
+                format!("label {shown} is never used"),
+            );
+        }
+    }
+
+    out
+}
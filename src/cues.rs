@@ -0,0 +1,145 @@
+//! Cue sheets, listing when the player has to give an input
+
+use crate::{
+    common::Tempo,
+    sim::{self, EventKind, SimError},
+    tickflow::Tickflow,
+};
+use std::io::{self, Write};
+
+/// A built-in sub that cues an input, optionally only counting inside one engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CueSub {
+    pub engine: Option<u32>,
+    pub sub: u32,
+}
+
+impl CueSub {
+    /// Parses either `<sub>` or `<engine>:<sub>`, with both numbers in hex
+    pub fn parse(text: &str) -> Option<Self> {
+        let hex = |c: &str| u32::from_str_radix(c.trim().trim_start_matches("0x"), 16).ok();
+        match text.split_once(':') {
+            Some((engine, sub)) => Some(Self {
+                engine: Some(hex(engine)?),
+                sub: hex(sub)?,
+            }),
+            None => Some(Self {
+                engine: None,
+                sub: hex(text)?,
+            }),
+        }
+    }
+
+    fn matches(&self, engine: Option<u32>, sub: u32) -> bool {
+        self.sub == sub && (self.engine.is_none() || self.engine == engine)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cue {
+    pub beat: f64,
+    pub seconds: Option<f64>,
+    pub offset: u32,
+    pub engine: Option<u32>,
+    pub kind: CueKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CueKind {
+    /// An `input` command
+    Input { arg0: u32, value: u32 },
+    /// A `sub` or `async_sub` that was given as a cue sub
+    Sub(u32),
+}
+
+/// Follows tickflow from its start label and lists every input cue in it
+///
+/// Both `input` commands and calls to any of the given cue subs count as cues.
+pub fn cue_sheet(
+    tickflow: &Tickflow,
+    tempo: Option<&Tempo>,
+    cue_subs: &[CueSub],
+    max_beats: f64,
+) -> Result<Vec<Cue>, SimError> {
+    let mut out = vec![];
+    for event in sim::simulate(tickflow, tempo, max_beats)? {
+        let kind = match event.kind {
+            EventKind::Input { arg0, value } => CueKind::Input { arg0, value },
+            EventKind::Sub(c) | EventKind::AsyncSub(c)
+                if cue_subs.iter().any(|s| s.matches(event.engine, c)) =>
+            {
+                CueKind::Sub(c)
+            }
+            _ => continue,
+        };
+        out.push(Cue {
+            beat: event.beat,
+            seconds: event.seconds,
+            offset: event.offset,
+            engine: event.engine,
+            kind,
+        });
+    }
+    Ok(out)
+}
+
+impl Cue {
+    fn kind_name(&self) -> &'static str {
+        match self.kind {
+            CueKind::Input { .. } => "input",
+            CueKind::Sub(_) => "sub",
+        }
+    }
+
+    fn value(&self) -> u32 {
+        match self.kind {
+            CueKind::Input { value, .. } => value,
+            CueKind::Sub(c) => c,
+        }
+    }
+
+    fn arg0(&self) -> u32 {
+        match self.kind {
+            CueKind::Input { arg0, .. } => arg0,
+            CueKind::Sub(_) => 0,
+        }
+    }
+}
+
+pub fn write_csv<W: Write>(cues: &[Cue], f: &mut W) -> io::Result<()> {
+    writeln!(f, "beat,seconds,offset,engine,kind,arg0,value")?;
+    for cue in cues {
+        writeln!(
+            f,
+            "{},{},{:#X},{},{},{},{:#X}",
+            cue.beat,
+            cue.seconds.map(|c| c.to_string()).unwrap_or_default(),
+            cue.offset,
+            cue.engine.map(|c| format!("{:#X}", c)).unwrap_or_default(),
+            cue.kind_name(),
+            cue.arg0(),
+            cue.value(),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_json<W: Write>(cues: &[Cue], f: &mut W) -> io::Result<()> {
+    writeln!(f, "[")?;
+    for (i, cue) in cues.iter().enumerate() {
+        let null = || "null".to_string();
+        writeln!(
+            f,
+            "  {{\"beat\": {}, \"seconds\": {}, \"offset\": {}, \"engine\": {}, \"kind\": \"{}\", \"arg0\": {}, \"value\": {}}}{}",
+            cue.beat,
+            cue.seconds.map(|c| c.to_string()).unwrap_or_else(null),
+            cue.offset,
+            cue.engine.map(|c| c.to_string()).unwrap_or_else(null),
+            cue.kind_name(),
+            cue.arg0(),
+            cue.value(),
+            if i + 1 == cues.len() { "" } else { "," },
+        )?;
+    }
+    writeln!(f, "]")
+}
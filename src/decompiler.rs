@@ -0,0 +1,182 @@
+//! Turning compiled tickflow back into source
+
+use crate::{
+    compiler::commands::{Arg0Type, ArgType, CmdDef, ID_ARGS, TICKOMPILER_COMMANDS},
+    tickflow::{Statement, Tickflow, ANN_POINTER, ANN_STRING, ANN_USTRING},
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, Write},
+};
+
+/// Writes tickflow source that compiles back to the given tickflow
+///
+/// If `constants` are given, a sub, category, engine or sound ID argument whose value belongs to
+/// exactly one constant is written as that constant's name. Negative integers are written in
+/// decimal.
+///
+/// A pointer into the middle of a statement is written as the label of that statement plus
+/// how far into it the pointer goes, like `loc_1C + 0x4`.
+pub fn decompile<W: Write>(
+    tickflow: &Tickflow,
+    constants: Option<&HashMap<String, i32>>,
+    f: &mut W,
+) -> io::Result<()> {
+    let mut by_value: HashMap<i32, Option<&str>> = HashMap::new();
+    for (name, value) in constants.into_iter().flatten() {
+        by_value
+            .entry(*value)
+            .and_modify(|c| *c = None)
+            .or_insert(Some(name));
+    }
+    let int = |value: u32| match (value as i32) < 0 {
+        true => (value as i32).to_string(),
+        false => format!("{:#X}", value),
+    };
+    let id = |value: u32| match by_value.get(&(value as i32)) {
+        Some(Some(name)) => name.to_string(),
+        _ => int(value),
+    };
+
+    let offsets = tickflow
+        .statements
+        .iter()
+        .map(Statement::offset)
+        .collect::<Vec<_>>();
+    // the offset of the statement that a pointer points into
+    let containing = |target: u32| {
+        let i = offsets.partition_point(|c| *c <= target);
+        offsets[i.saturating_sub(1)]
+    };
+    for (offset, what) in [(tickflow.start, "start"), (tickflow.assets, "assets")] {
+        if offsets.binary_search(&offset).is_err() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the {what} offset {offset:#X} isn't at the start of a statement"),
+            ))?
+        }
+    }
+
+    let mut targets = BTreeSet::new();
+    for statement in &tickflow.statements {
+        if let Statement::Command { args, anns, .. } = statement {
+            for (i, ann) in anns {
+                if *ann == ANN_POINTER {
+                    targets.insert(containing(args[*i as usize]));
+                }
+            }
+        }
+    }
+    let label = |offset: u32| {
+        if offset == tickflow.start {
+            "start".to_string()
+        } else if offset == tickflow.assets {
+            "assets".to_string()
+        } else {
+            format!("loc_{:X}", offset)
+        }
+    };
+
+    writeln!(f, "#index {:#X}", tickflow.index)?;
+    for statement in &tickflow.statements {
+        let offset = statement.offset();
+        if offset == tickflow.start {
+            writeln!(f, "\nstart:")?;
+        }
+        if offset == tickflow.assets {
+            writeln!(f, "\nassets:")?;
+        }
+        if targets.contains(&offset) && offset != tickflow.start && offset != tickflow.assets {
+            writeln!(f, "\n{}:", label(offset))?;
+        }
+
+        let (cmd, arg0, args, anns) = match statement {
+            Statement::Data { data, .. } => {
+                let bytes = data.iter().map(|c| format!("{:#X}", c)).collect::<Vec<_>>();
+                writeln!(f, "bytes {}", bytes.join(", "))?;
+                continue;
+            }
+            Statement::Command {
+                cmd,
+                arg0,
+                args,
+                anns,
+                ..
+            } => (*cmd, *arg0, args, anns),
+        };
+
+        let kinds = (0..args.len())
+            .map(
+                |i| match anns.iter().find(|c| c.0 == i as u32).map(|c| c.1) {
+                    Some(ANN_POINTER) => ArgType::Label,
+                    Some(c @ (ANN_STRING | ANN_USTRING)) => ArgType::String(c == ANN_USTRING),
+                    _ => ArgType::Int,
+                },
+            )
+            .collect::<Vec<_>>();
+        let found = find_command(cmd, arg0, &kinds);
+
+        let mut values = vec![];
+        for (i, (arg, kind)) in args.iter().zip(&kinds).enumerate() {
+            values.push(match kind {
+                ArgType::Label => {
+                    let base = containing(*arg);
+                    match *arg - base {
+                        0 => label(base),
+                        c => format!("{} + {:#X}", label(base), c),
+                    }
+                }
+                ArgType::String(is_unicode) => {
+                    let text = tickflow.string_at(*arg, *is_unicode).unwrap_or_default();
+                    let text = text.replace('\\', "\\\\").replace('"', "\\\"");
+                    format!("{}\"{}\"", if *is_unicode { "u" } else { "" }, text)
+                }
+                _ => match matches!(found, Some((name, _)) if ID_ARGS.contains(&(name, i))) {
+                    true => id(*arg),
+                    false => int(*arg),
+                },
+            });
+        }
+
+        let head = match found {
+            Some((name, Arg0Type::Argument)) => {
+                values.insert(0, int(arg0));
+                name.to_string()
+            }
+            Some((name, Arg0Type::Any)) if arg0 != 0 => format!("{}<{}>", name, int(arg0)),
+            Some((name, _)) => name.to_string(),
+            None => format!("{:#X}<{}>", cmd, int(arg0)),
+        };
+        if values.is_empty() {
+            writeln!(f, "{}", head)?;
+        } else {
+            writeln!(f, "{} {}", head, values.join(", "))?;
+        }
+    }
+    Ok(())
+}
+
+/// Finds the named command that would compile to this command with these kinds of arguments
+fn find_command(cmd: u16, arg0: u32, kinds: &[ArgType]) -> Option<(&'static str, Arg0Type)> {
+    let fits = |def: &CmdDef| {
+        def.calc_arg_range().contains(&kinds.len())
+            && kinds.iter().zip(def.args).all(|(kind, def)| match kind {
+                ArgType::Int => matches!(def, ArgType::Int | ArgType::Opt(_)),
+                c => c == def,
+            })
+    };
+    let mut found = None;
+    for (name, def) in TICKOMPILER_COMMANDS {
+        if def.num != cmd {
+            continue;
+        }
+        match def.arg0 {
+            Arg0Type::Set(c) if c == arg0 && fits(def) => return Some((name, def.arg0)),
+            // the argument checker is skipped for non-standard arg0s
+            Arg0Type::Any if arg0 != 0 || fits(def) => found = found.or(Some((*name, def.arg0))),
+            Arg0Type::Argument if fits(def) => found = found.or(Some((*name, def.arg0))),
+            _ => (),
+        }
+    }
+    found
+}
@@ -3,5 +3,10 @@ pub mod common;
 pub mod btks;
 pub mod c00;
 pub mod compiler;
+pub mod cues;
+pub mod decompiler;
+pub mod midi;
+pub mod sim;
+pub mod tickflow;
 
 pub use btks::BTKS;
@@ -1,6 +1,17 @@
 use clap::{Parser, Subcommand};
-use deckompiler::btks::BTKS;
-use std::{fs::File, io::Result as IOResult, path::PathBuf};
+use deckompiler::{
+    btks::BTKS,
+    common::Tempo,
+    compiler::{link, preprocess::Preprocessor, CompiledFileType},
+    cues::{self, CueSub},
+    decompiler, sim,
+    tickflow::Tickflow,
+};
+use std::{
+    fs::{self, File},
+    io::{Result as IOResult, Write},
+    path::PathBuf,
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -20,6 +31,86 @@ enum Commands {
         /// Optional tempo files to include in the .btk
         tempo: Vec<PathBuf>,
     },
+    /// Convert the tempo track of a MIDI file to a .tempo file
+    MidiToTempo {
+        /// The path of the input .mid file
+        midi: PathBuf,
+        /// The tempo ID to give the .tempo file, in hex
+        #[clap(value_parser = parse_hex)]
+        id: u32,
+        /// The path for the output .tempo file (defaults to MIDI with .tempo extension)
+        tempo: Option<PathBuf>,
+        /// Write `@time` for times no BPM gives back, which Tickompiler can't read
+        #[clap(short, long)]
+        extended: bool,
+    },
+    /// Convert a .tempo file to a MIDI file with its tempo map
+    TempoToMidi {
+        /// The path of the input .tempo file
+        tempo: PathBuf,
+        /// The path for the output .mid file (defaults to TEMPO with .mid extension)
+        midi: Option<PathBuf>,
+    },
+    /// Print a timeline of the events in a Tickompiler .bin file, as they'd happen in-game
+    Simulate {
+        /// The path of the input .bin file
+        bin: PathBuf,
+        /// A .tempo file to calculate the time of each event in seconds with
+        #[clap(short, long)]
+        tempo: Option<PathBuf>,
+        /// Beat to stop the simulation at
+        #[clap(short, long, default_value_t = 2000.0)]
+        max_beats: f64,
+    },
+    /// Write a cue sheet with the beat of every input cue in a Tickompiler .bin file
+    Cues {
+        /// The path of the input .bin file
+        bin: PathBuf,
+        /// The path for the output cue sheet (defaults to BIN with .csv or .json extension)
+        out: Option<PathBuf>,
+        /// A .tempo file to calculate the time of each cue in seconds with
+        #[clap(short, long)]
+        tempo: Option<PathBuf>,
+        /// Built-in subs that also count as cues, as `<sub>` or `<engine>:<sub>` in hex
+        #[clap(short, long, value_parser = parse_cue_sub)]
+        cue_sub: Vec<CueSub>,
+        /// Write JSON instead of CSV
+        #[clap(short, long)]
+        json: bool,
+        /// Beat to stop looking for cues at
+        #[clap(short, long, default_value_t = 2000.0)]
+        max_beats: f64,
+    },
+    /// Convert a Tickompiler .bin file back to tickflow source
+    Decompile {
+        /// The path of the input .bin file
+        bin: PathBuf,
+        /// The path for the output .tickflow file (defaults to BIN with .tickflow extension)
+        tickflow: Option<PathBuf>,
+        /// A tickflow file or header whose `#define` constants name sub, engine and sound IDs in the output
+        #[clap(short, long)]
+        constants: Option<PathBuf>,
+    },
+    /// Link object modules made with `not_tickompiler --object` into one Tickompiler .bin file
+    Link {
+        /// The object modules, starting with the game's own
+        #[clap(required = true)]
+        modules: Vec<PathBuf>,
+        /// The path for the output .bin file
+        #[clap(short, long)]
+        out: PathBuf,
+        /// Write a Spicerack .btk instead
+        #[clap(short, long)]
+        btks: bool,
+    },
+}
+
+fn parse_cue_sub(text: &str) -> Result<CueSub, String> {
+    CueSub::parse(text).ok_or_else(|| format!("invalid cue sub '{}'", text))
+}
+
+fn parse_hex(num: &str) -> Result<u32, String> {
+    u32::from_str_radix(num.trim_start_matches("0x"), 16).map_err(|e| e.to_string())
 }
 
 fn main() -> Result<(), i32> {
@@ -55,6 +146,111 @@ fn run() -> IOResult<()> {
                 c.to_btks_file(&mut f)?;
             }
         }
+        Commands::MidiToTempo {
+            midi,
+            id,
+            tempo: tempo_path,
+            extended,
+        } => {
+            let tempo_path = match tempo_path {
+                Some(c) => c,
+                None => midi.with_extension("tempo"),
+            };
+
+            let tempo = Tempo::from_midi(&mut File::open(midi)?, id)?;
+            let mut f = File::create(tempo_path)?;
+            f.write_all(tempo.to_tickompiler_file(extended).as_bytes())?;
+        }
+        Commands::TempoToMidi {
+            tempo,
+            midi: midi_path,
+        } => {
+            let midi_path = match midi_path {
+                Some(c) => c,
+                None => tempo.with_extension("mid"),
+            };
+
+            let tempo = Tempo::from_tickompiler_file(&fs::read_to_string(tempo)?)?;
+            tempo.to_midi(&mut File::create(midi_path)?)?;
+        }
+        Commands::Simulate {
+            bin,
+            tempo,
+            max_beats,
+        } => {
+            let tickflow = Tickflow::from_bin(&mut File::open(bin)?)?;
+            let tempo = match tempo {
+                Some(c) => Some(Tempo::from_tickompiler_file(&fs::read_to_string(c)?)?),
+                None => None,
+            };
+
+            for event in sim::simulate(&tickflow, tempo.as_ref(), max_beats)? {
+                let seconds = match event.seconds {
+                    Some(c) => format!("{:.3}s", c),
+                    None => "-".to_string(),
+                };
+                println!(
+                    "{:>10.3} {:>10} {:>#8X}  {}",
+                    event.beat, seconds, event.offset, event.kind
+                );
+            }
+        }
+        Commands::Cues {
+            bin,
+            out,
+            tempo,
+            cue_sub,
+            json,
+            max_beats,
+        } => {
+            let out = match out {
+                Some(c) => c,
+                None => bin.with_extension(if json { "json" } else { "csv" }),
+            };
+            let tickflow = Tickflow::from_bin(&mut File::open(bin)?)?;
+            let tempo = match tempo {
+                Some(c) => Some(Tempo::from_tickompiler_file(&fs::read_to_string(c)?)?),
+                None => None,
+            };
+
+            let cues = cues::cue_sheet(&tickflow, tempo.as_ref(), &cue_sub, max_beats)?;
+            let mut f = File::create(out)?;
+            if json {
+                cues::write_json(&cues, &mut f)?;
+            } else {
+                cues::write_csv(&cues, &mut f)?;
+            }
+        }
+        Commands::Decompile {
+            bin,
+            tickflow: tickflow_path,
+            constants,
+        } => {
+            let tickflow_path = match tickflow_path {
+                Some(c) => c,
+                None => bin.with_extension("tickflow"),
+            };
+            let tickflow = Tickflow::from_bin(&mut File::open(bin)?)?;
+            let constants = match constants {
+                Some(c) => {
+                    let mut preprocessor = Preprocessor::new();
+                    preprocessor.process_file(c)?;
+                    Some(preprocessor.constants)
+                }
+                None => None,
+            };
+
+            let mut f = File::create(tickflow_path)?;
+            decompiler::decompile(&tickflow, constants.as_ref(), &mut f)?;
+        }
+        Commands::Link { modules, out, btks } => {
+            let filetype = if btks {
+                CompiledFileType::BTKS
+            } else {
+                CompiledFileType::Tickompiler
+            };
+            link::link_files(&modules, out, filetype)?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,267 @@
+//! Conversion between tempos and the tempo map of Standard MIDI Files
+
+use crate::common::{LoopFlags, Tempo, TempoVal};
+use bytestream::{ByteOrder::BigEndian as BE, StreamReader, StreamWriter};
+use std::io::{self, Read, Write};
+
+/// Ticks per quarter note used for exported MIDI files
+const EXPORT_DIVISION: u16 = 480;
+/// Tempo MIDI files start with if they don't set one (120 BPM)
+const DEFAULT_USPQ: u32 = 500000;
+
+const META_MARKER: u8 = 0x06;
+const META_END_OF_TRACK: u8 = 0x2F;
+const META_TEMPO: u8 = 0x51;
+
+/// Prefix for the marker events that hold a tempo value's loop value
+const LOOP_MARKER: &str = "loop=";
+
+fn error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn read_vlq<R: Read>(f: &mut R) -> io::Result<u32> {
+    let mut out = 0;
+    for _ in 0..4 {
+        let byte = u8::read_from(f, BE)?;
+        out = (out << 7) | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(out);
+        }
+    }
+    Err(error("variable-length value is too long"))
+}
+
+fn write_vlq<W: Write>(f: &mut W, mut value: u32) -> io::Result<()> {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value != 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.reverse();
+    f.write_all(&bytes)
+}
+
+fn read_chunk<R: Read>(f: &mut R) -> io::Result<([u8; 4], Vec<u8>)> {
+    let mut magic = [0; 4];
+    f.read_exact(&mut magic)?;
+    let size = u32::read_from(f, BE)?;
+    let mut data = vec![0; size as usize];
+    f.read_exact(&mut data)?;
+    Ok((magic, data))
+}
+
+/// The events from a MIDI file that matter for the tempo map
+#[derive(Debug, Default)]
+struct TempoMap {
+    /// (tick, microseconds per quarter note)
+    tempos: Vec<(u32, u32)>,
+    /// (tick, loop value)
+    loops: Vec<(u32, u32)>,
+    end: u32,
+}
+
+impl TempoMap {
+    fn read_track(&mut self, mut track: &[u8]) -> io::Result<()> {
+        let mut tick = 0;
+        let mut status = 0u8;
+        while !track.is_empty() {
+            tick += read_vlq(&mut track)?;
+            let first = u8::read_from(&mut track, BE)?;
+            // without a status byte, the last one is reused (running status)
+            let (event, has_status) = if first & 0x80 != 0 {
+                (first, true)
+            } else if status != 0 {
+                (status, false)
+            } else {
+                Err(error("MIDI track uses running status before any event"))?
+            };
+            match event {
+                0xFF => {
+                    let kind = u8::read_from(&mut track, BE)?;
+                    let len = read_vlq(&mut track)?;
+                    let data = read_bytes(&mut track, len as usize)?;
+                    match kind {
+                        META_TEMPO if data.len() == 3 => {
+                            let uspq = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                            if uspq == 0 {
+                                Err(error(format!(
+                                    "tempo change at tick {tick} has a length of 0"
+                                )))?
+                            }
+                            self.tempos.push((tick, uspq))
+                        }
+                        META_MARKER => {
+                            let text = String::from_utf8_lossy(data);
+                            if let Some(c) = text.strip_prefix(LOOP_MARKER) {
+                                let loop_val = c
+                                    .trim()
+                                    .parse()
+                                    .map_err(|_| error(format!("invalid loop marker '{text}'")))?;
+                                self.loops.push((tick, loop_val));
+                            }
+                        }
+                        META_END_OF_TRACK => break,
+                        _ => (),
+                    }
+                }
+                0xF0 | 0xF7 => {
+                    let len = read_vlq(&mut track)?;
+                    read_bytes(&mut track, len as usize)?;
+                }
+                _ => {
+                    status = event;
+                    let len = match event & 0xF0 {
+                        0xC0 | 0xD0 => 1,
+                        _ => 2,
+                    };
+                    read_bytes(&mut track, len - !has_status as usize)?;
+                }
+            }
+        }
+        self.end = self.end.max(tick);
+        Ok(())
+    }
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if data.len() < len {
+        Err(error("MIDI track ends in the middle of an event"))?
+    }
+    let (out, rest) = data.split_at(len);
+    *data = rest;
+    Ok(out)
+}
+
+impl Tempo {
+    /// Builds a tempo with the given ID out of the tempo track of a Standard MIDI File
+    ///
+    /// Every tempo change starts a new tempo value, which lasts until the next change or, for
+    /// the last one, the end of the longest track. Marker events named `loop=<value>` set the
+    /// loop value of the tempo value starting at their position, and without one the last
+    /// value ends the tempo.
+    pub fn from_midi<R: Read>(f: &mut R, id: u32) -> io::Result<Self> {
+        let (magic, header) = read_chunk(f)?;
+        if &magic != b"MThd" || header.len() < 6 {
+            Err(error("not a Standard MIDI File"))?
+        }
+        let num_tracks = u16::from_be_bytes([header[2], header[3]]);
+        let division = u16::from_be_bytes([header[4], header[5]]);
+        if division & 0x8000 != 0 {
+            Err(error("MIDI files with SMPTE timing aren't supported"))?
+        }
+
+        let mut map = TempoMap::default();
+        let mut tracks_read = 0;
+        while tracks_read < num_tracks {
+            let (magic, track) = read_chunk(f)?;
+            // unknown chunks are meant to be skipped
+            if &magic == b"MTrk" {
+                map.read_track(&track)?;
+                tracks_read += 1;
+            }
+        }
+
+        map.tempos.sort_by_key(|c| c.0);
+        map.loops.sort_by_key(|c| c.0);
+        if map.tempos.first().map(|c| c.0) != Some(0) {
+            map.tempos.insert(0, (0, DEFAULT_USPQ));
+        }
+        let mut bounds = map
+            .tempos
+            .iter()
+            .chain(map.loops.iter())
+            .map(|c| c.0)
+            .filter(|c| *c < map.end)
+            .collect::<Vec<_>>();
+        bounds.sort();
+        bounds.dedup();
+
+        let mut data = vec![];
+        for (i, start) in bounds.iter().enumerate() {
+            let end = bounds.get(i + 1).copied().unwrap_or(map.end);
+            let ticks = (end - start) as u64;
+            // last tempo change at or before this point
+            let uspq = map
+                .tempos
+                .iter()
+                .rev()
+                .find(|c| c.0 <= *start)
+                .map(|c| c.1)
+                .unwrap_or(DEFAULT_USPQ) as u64;
+            let loop_val = map
+                .loops
+                .iter()
+                .rev()
+                .find(|c| c.0 == *start)
+                .map(|c| LoopFlags::from_bits(c.1))
+                .unwrap_or(match i == bounds.len() - 1 {
+                    true => LoopFlags::END,
+                    false => LoopFlags::default(),
+                });
+            let time_divisor = division as u64 * 1000000;
+            let time = ((ticks * uspq * 32000 + time_divisor / 2) / time_divisor) as u32;
+            if time == 0 {
+                Err(error(format!(
+                    "tempo value at tick {start} is too short to take any time"
+                )))?
+            }
+            data.push(TempoVal {
+                beats: (ticks as f64 / division as f64) as f32,
+                time,
+                loop_val,
+            });
+        }
+
+        let tempo = Self {
+            id,
+            data,
+            streamed: None,
+        };
+        tempo.validate()?;
+        Ok(tempo)
+    }
+
+    /// Writes the tempo as the tempo map of a single-track Standard MIDI File
+    ///
+    /// Loop values other than the default are kept as `loop=<value>` marker events.
+    pub fn to_midi<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        let mut track = vec![];
+        let mut tick = 0.0;
+        let mut last_tick = 0;
+        for val in &self.data {
+            let uspq = (val.time as f64 * 1000000.0 / 32000.0 / val.beats as f64).round() as u32;
+            let this_tick = (tick * EXPORT_DIVISION as f64).round() as u32;
+
+            // values with negative beats would go back in time, so they end up with no length
+            write_vlq(&mut track, this_tick.saturating_sub(last_tick))?;
+            track.extend([0xFF, META_TEMPO, 3]);
+            // a tempo of 0 isn't allowed in MIDI files, so values that take no time get the
+            // shortest one there is
+            track.extend(&uspq.clamp(1, 0xFFFFFF).to_be_bytes()[1..]);
+            if val.loop_val != LoopFlags::default() {
+                let text = format!("{LOOP_MARKER}{}", val.loop_val.bits());
+                write_vlq(&mut track, 0)?;
+                track.extend([0xFF, META_MARKER]);
+                write_vlq(&mut track, text.len() as u32)?;
+                track.extend(text.as_bytes());
+            }
+
+            last_tick = last_tick.max(this_tick);
+            tick += val.beats as f64;
+        }
+        let end_tick = (tick * EXPORT_DIVISION as f64).round() as u32;
+        write_vlq(&mut track, end_tick.saturating_sub(last_tick))?;
+        track.extend([0xFF, META_END_OF_TRACK, 0]);
+
+        f.write_all(b"MThd")?;
+        6u32.write_to(f, BE)?;
+        0u16.write_to(f, BE)?; // format 0
+        1u16.write_to(f, BE)?;
+        EXPORT_DIVISION.write_to(f, BE)?;
+        f.write_all(b"MTrk")?;
+        (track.len() as u32).write_to(f, BE)?;
+        f.write_all(&track)
+    }
+}
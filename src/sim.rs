@@ -0,0 +1,397 @@
+//! Simulation of tickflow timing, without any of the graphics or gameplay
+
+use crate::{
+    common::Tempo,
+    tickflow::{Statement, Tickflow},
+};
+use std::{
+    fmt::{self, Display},
+    io,
+};
+
+/// Amount of tickflow ticks in a beat
+pub const TICKS_PER_BEAT: u32 = 48;
+/// Speed value for tickflow running at normal speed
+pub const SPEED_NORMAL: u32 = 0x100;
+/// Commands a thread can run without its beat moving forward before the simulation gives up
+const MAX_STEPS: usize = 1000000;
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub beat: f64,
+    /// Time of the event in seconds, if a tempo was given
+    pub seconds: Option<f64>,
+    /// Position of the command that caused the event
+    pub offset: u32,
+    /// Engine the tickflow was in when the event happened, if it had been set
+    pub engine: Option<u32>,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum EventKind {
+    Input {
+        arg0: u32,
+        value: u32,
+    },
+    PlaySfx(u32),
+    Engine(u32),
+    LoadAsset {
+        kind: AssetKind,
+        slot: u32,
+        path: Option<String>,
+    },
+    /// Built-in subs are part of the game, so they can't be followed
+    Sub(u32),
+    AsyncSub(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+    Model,
+    Cellanim,
+    Effect,
+    Layout,
+    Sfx,
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Input { arg0, value } => write!(f, "input<{}> {:#X}", arg0, value),
+            Self::PlaySfx(c) => write!(f, "play_sfx {:#X}", c),
+            Self::Engine(c) => write!(f, "engine {:#X}", c),
+            Self::LoadAsset { kind, slot, path } => {
+                let name = match kind {
+                    AssetKind::Model => "set_model",
+                    AssetKind::Cellanim => "set_cellanim",
+                    AssetKind::Effect => "set_effect",
+                    AssetKind::Layout => "set_layout",
+                    AssetKind::Sfx => "set_sfx",
+                };
+                match path {
+                    Some(path) => write!(f, "{} {:#X}, \"{}\"", name, slot, path),
+                    None => write!(f, "{} {:#X}", name, slot),
+                }
+            }
+            Self::Sub(c) => write!(f, "sub {:#X}", c),
+            Self::AsyncSub(c) => write!(f, "async_sub {:#X}", c),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SimError {
+    /// A pointer argument doesn't point to the start of a command
+    BadPointer { offset: u32, target: u32 },
+    /// A goto has no label with its number to go to
+    MissingLabel { offset: u32, label: u32 },
+    /// An if, else, switch or break has no end to skip to
+    UnclosedBlock { offset: u32 },
+    /// Tickflow ran into a data block
+    RanIntoData { offset: u32 },
+    /// Too many commands ran without a rest, which most likely means an infinite loop
+    StepLimit { offset: u32 },
+}
+
+impl From<SimError> for io::Error {
+    fn from(value: SimError) -> Self {
+        Self::new(
+            io::ErrorKind::Other,
+            match value {
+                SimError::BadPointer { offset, target } => {
+                    format!("Command at {offset:#X} points to {target:#X}, which isn't a command")
+                }
+                SimError::MissingLabel { offset, label } => {
+                    format!("Goto at {offset:#X} can't find label {label}")
+                }
+                SimError::UnclosedBlock { offset } => {
+                    format!("Command at {offset:#X} has no matching end")
+                }
+                SimError::RanIntoData { offset } => {
+                    format!("Tickflow ran into the data block at {offset:#X}")
+                }
+                SimError::StepLimit { offset } => {
+                    format!(
+                        "Tickflow at {offset:#X} never rests, there's probably an infinite loop"
+                    )
+                }
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Thread {
+    pos: usize,
+    /// Start of the function the thread is in, where gotos look for labels from
+    func_start: usize,
+    beat: f64,
+    speed: u32,
+    engine: Option<u32>,
+    condvar: i32,
+    condvar_stack: Vec<i32>,
+    /// Return position and function start for every call
+    calls: Vec<(usize, usize)>,
+    /// Commands run since the beat last moved forward, which carries over zero-tick rests
+    steps: usize,
+}
+
+impl Thread {
+    fn new(pos: usize, beat: f64, speed: u32) -> Self {
+        Self {
+            pos,
+            func_start: pos,
+            beat,
+            speed,
+            engine: None,
+            condvar: 0,
+            condvar_stack: vec![],
+            calls: vec![],
+            steps: 0,
+        }
+    }
+
+    fn rest(&mut self, ticks: u32) {
+        let speed = self.speed.max(1) as f64;
+        self.beat += ticks as f64 / TICKS_PER_BEAT as f64 * SPEED_NORMAL as f64 / speed;
+    }
+}
+
+enum Step {
+    Rest,
+    End,
+}
+
+/// Runs tickflow from its start label, collecting a timeline of the events that happen
+///
+/// Every thread (the main one plus any started by `async_call`) is followed until it ends or
+/// goes past `max_beats`. Commands that would set the condvar depending on the game's state
+/// are ignored, so conditions only see what's set by the condvar commands themselves.
+/// `speed_relative` is treated as multiplying the speed by its first argument divided by
+/// its second one, which is a guess rather than known behaviour.
+pub fn simulate(
+    tickflow: &Tickflow,
+    tempo: Option<&Tempo>,
+    max_beats: f64,
+) -> Result<Vec<Event>, SimError> {
+    let start = pointer_pos(tickflow, tickflow.start, tickflow.start)?;
+    let mut threads = vec![Thread::new(start, 0.0, SPEED_NORMAL)];
+    let mut events = vec![];
+
+    // always run the thread that's the furthest behind
+    while let Some(i) =
+        (0..threads.len()).min_by(|a, b| threads[*a].beat.total_cmp(&threads[*b].beat))
+    {
+        if threads[i].beat > max_beats {
+            break;
+        }
+        let mut new_threads = vec![];
+        if let Step::End = run_thread(tickflow, &mut threads[i], &mut events, &mut new_threads)? {
+            threads.remove(i);
+        }
+        threads.extend(new_threads);
+    }
+
+    events.retain(|c: &Event| c.beat <= max_beats);
+    events.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+    if let Some(tempo) = tempo {
+        for event in &mut events {
+            event.seconds = Some(tempo.beats_to_seconds(event.beat));
+        }
+    }
+    Ok(events)
+}
+
+fn pointer_pos(tickflow: &Tickflow, offset: u32, target: u32) -> Result<usize, SimError> {
+    tickflow
+        .position_of(target)
+        .ok_or(SimError::BadPointer { offset, target })
+}
+
+/// Runs a thread until it rests or ends
+fn run_thread(
+    tickflow: &Tickflow,
+    thread: &mut Thread,
+    events: &mut Vec<Event>,
+    new_threads: &mut Vec<Thread>,
+) -> Result<Step, SimError> {
+    while thread.steps < MAX_STEPS {
+        thread.steps += 1;
+        let Some(statement) = tickflow.statements.get(thread.pos) else {
+            return Ok(Step::End);
+        };
+        let Statement::Command {
+            offset,
+            cmd,
+            arg0,
+            args,
+            ..
+        } = statement
+        else {
+            Err(SimError::RanIntoData {
+                offset: statement.offset(),
+            })?
+        };
+        let (offset, arg0) = (*offset, *arg0);
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+        let (beat, engine) = (thread.beat, thread.engine);
+        let mut event = |kind| {
+            events.push(Event {
+                beat,
+                seconds: None,
+                offset,
+                engine,
+                kind,
+            })
+        };
+        thread.pos += 1;
+
+        match (*cmd, arg0) {
+            (0x0, _) => event(EventKind::AsyncSub(arg(0))),
+            (0x2, _) => {
+                let pos = pointer_pos(tickflow, offset, arg(0))?;
+                let mut new_thread = Thread::new(pos, thread.beat, thread.speed);
+                new_thread.engine = thread.engine;
+                new_thread.rest(arg(1));
+                new_threads.push(new_thread);
+            }
+            (0x4, _) => event(EventKind::Sub(arg(0))),
+            (0x6, _) => {
+                let pos = pointer_pos(tickflow, offset, arg(0))?;
+                thread.calls.push((thread.pos, thread.func_start));
+                thread.pos = pos;
+                thread.func_start = pos;
+            }
+            (0x7, _) => match thread.calls.pop() {
+                Some((pos, func_start)) => {
+                    thread.pos = pos;
+                    thread.func_start = func_start;
+                }
+                None => return Ok(Step::End),
+            },
+            (0x8, _) => return Ok(Step::End),
+            (0xA, _) => thread.condvar = arg(0) as i32,
+            (0xB, _) => thread.condvar = thread.condvar.wrapping_add(arg(0) as i32),
+            (0xC, _) => thread.condvar_stack.push(thread.condvar),
+            (0xD, _) => thread.condvar = thread.condvar_stack.pop().unwrap_or(0),
+            (0xE, _) => {
+                let before = thread.beat;
+                thread.rest(arg0);
+                // a rest of 0 still lets other threads run, but doesn't stop an endless loop
+                // from counting towards the limit
+                if thread.beat > before {
+                    thread.steps = 0;
+                }
+                return Ok(Step::Rest);
+            }
+            (0x15, _) => {
+                thread.pos = tickflow.statements[thread.func_start..]
+                    .iter()
+                    .position(|c| {
+                        matches!(c, Statement::Command { cmd: 0x14, arg0: label, .. } if *label == arg0)
+                    })
+                    .map(|c| thread.func_start + c + 1)
+                    .ok_or(SimError::MissingLabel { offset, label: arg0 })?;
+            }
+            (0x16, _) => {
+                let value = arg(0) as i32;
+                let condition = match arg0 {
+                    0 => thread.condvar == value,
+                    1 => thread.condvar != value,
+                    2 => thread.condvar < value,
+                    3 => thread.condvar <= value,
+                    4 => thread.condvar > value,
+                    _ => thread.condvar >= value,
+                };
+                if !condition {
+                    thread.pos = skip_block(tickflow, thread.pos, offset, 0x16, 0x18, &[0x17])?;
+                }
+            }
+            (0x17, _) => thread.pos = skip_block(tickflow, thread.pos, offset, 0x16, 0x18, &[])?,
+            (0x19, _) => {
+                let condvar = thread.condvar as u32;
+                let end = skip_block(tickflow, thread.pos, offset, 0x19, 0x1D, &[])?;
+                // cases at the top level of this switch, up to its endswitch
+                let mut depth = 0;
+                let mut default = None;
+                let mut case = None;
+                for (i, c) in tickflow.statements[thread.pos..end].iter().enumerate() {
+                    let Statement::Command { cmd, arg0, .. } = c else {
+                        continue;
+                    };
+                    match cmd {
+                        0x19 => depth += 1,
+                        0x1D => depth -= 1,
+                        0x1A if depth == 0 && *arg0 == condvar => {
+                            case = Some(thread.pos + i + 1);
+                            break;
+                        }
+                        0x1C if depth == 0 => default = Some(thread.pos + i + 1),
+                        _ => (),
+                    }
+                }
+                thread.pos = case.or(default).unwrap_or(end);
+            }
+            (0x1B, _) => thread.pos = skip_block(tickflow, thread.pos, offset, 0x19, 0x1D, &[])?,
+            (0x24, _) => thread.speed = arg(0),
+            // guessed from the argument names, this hasn't been checked against the game
+            (0x25, _) if arg(1) != 0 => {
+                thread.speed = (thread.speed as u64 * arg(0) as u64 / arg(1) as u64) as u32;
+            }
+            (0x28, _) => {
+                event(EventKind::Engine(arg(0)));
+                thread.engine = Some(arg(0));
+            }
+            (0x31 | 0x35 | 0x39 | 0x3E, 0) | (0x5D, _) => {
+                let kind = match cmd {
+                    0x31 => AssetKind::Model,
+                    0x35 => AssetKind::Cellanim,
+                    0x39 => AssetKind::Effect,
+                    0x3E => AssetKind::Layout,
+                    _ => AssetKind::Sfx,
+                };
+                event(EventKind::LoadAsset {
+                    kind,
+                    slot: arg(0),
+                    path: tickflow.string_at(arg(1), true),
+                })
+            }
+            (0x40, _) => event(EventKind::PlaySfx(arg(0))),
+            (0x6A, _) => event(EventKind::Input {
+                arg0,
+                value: arg(0),
+            }),
+            _ => (),
+        }
+    }
+    Err(SimError::StepLimit {
+        offset: tickflow.statements[thread.pos.min(tickflow.statements.len() - 1)].offset(),
+    })
+}
+
+/// Finds the position right after the end of a block (or one of the given commands that split
+/// it, like else), skipping over any blocks of the same kind nested inside
+fn skip_block(
+    tickflow: &Tickflow,
+    pos: usize,
+    offset: u32,
+    open: u16,
+    close: u16,
+    splits: &[u16],
+) -> Result<usize, SimError> {
+    let mut depth = 0;
+    for (i, statement) in tickflow.statements[pos..].iter().enumerate() {
+        let Statement::Command { cmd, .. } = statement else {
+            continue;
+        };
+        if *cmd == open {
+            depth += 1;
+        } else if *cmd == close && depth > 0 {
+            depth -= 1;
+        } else if depth == 0 && (*cmd == close || splits.contains(cmd)) {
+            return Ok(pos + i + 1);
+        }
+    }
+    Err(SimError::UnclosedBlock { offset })
+}
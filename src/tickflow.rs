@@ -0,0 +1,240 @@
+//! Reading of compiled tickflow, as found in Tickompiler .bin files
+
+use crate::c00::TickompilerBinary;
+use bytestream::{ByteOrder, StreamReader};
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+};
+
+/// Annotation code for pointers to other tickflow
+pub const ANN_POINTER: u32 = 0;
+/// Annotation code for pointers to UTF-16 strings
+pub const ANN_USTRING: u32 = 1;
+/// Annotation code for pointers to ASCII strings
+pub const ANN_STRING: u32 = 2;
+/// Annotation code for raw data blocks
+pub const ANN_DATA: u32 = 3;
+
+#[derive(Debug, Clone)]
+pub enum Statement {
+    Command {
+        /// Position in the tickflow data, not counting argument annotations
+        offset: u32,
+        cmd: u16,
+        arg0: u32,
+        args: Vec<u32>,
+        /// Argument annotations, as (argument index, annotation code)
+        anns: Vec<(u32, u32)>,
+    },
+    Data {
+        offset: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Statement {
+    pub fn offset(&self) -> u32 {
+        match self {
+            Self::Command { offset, .. } | Self::Data { offset, .. } => *offset,
+        }
+    }
+}
+
+/// Compiled tickflow, split into its statements and string data
+#[derive(Debug, Clone)]
+pub struct Tickflow {
+    pub index: u32,
+    pub start: u32,
+    pub assets: u32,
+    pub statements: Vec<Statement>,
+    /// Position the string data starts at, which is also the size of the tickflow
+    pub strings_offset: u32,
+    pub strings: Vec<u8>,
+}
+
+impl Tickflow {
+    /// Reads a Tickompiler .bin file
+    pub fn from_bin<F: Read>(f: &mut F) -> io::Result<Self> {
+        let index = u32::read_from(f, ByteOrder::LittleEndian)?;
+        let start = u32::read_from(f, ByteOrder::LittleEndian)?;
+        let assets = u32::read_from(f, ByteOrder::LittleEndian)?;
+        Self::from_data(f, index, start, assets)
+    }
+
+    pub fn from_tickompiler_binary(bin: &TickompilerBinary) -> io::Result<Self> {
+        Self::from_data(&mut &bin.data[..], bin.index, bin.start, bin.assets)
+    }
+
+    /// Reads the tickflow and string data that come after the header of a .bin file
+    pub fn from_data<F: Read>(f: &mut F, index: u32, start: u32, assets: u32) -> io::Result<Self> {
+        let mut statements = vec![];
+        let mut offset = 0;
+        loop {
+            let mut op_int = u32::read_from(f, ByteOrder::LittleEndian)?;
+            if op_int == 0xFFFFFFFE {
+                // 0xFFFFFFFE (-2) indicates start of string data
+                break;
+            }
+            let mut anns = vec![];
+            if op_int == 0xFFFFFFFF {
+                // 0xFFFFFFFF (-1) indicates an 'args' section
+                let amount = u32::read_from(f, ByteOrder::LittleEndian)?;
+                for _ in 0..amount {
+                    let ann = u32::read_from(f, ByteOrder::LittleEndian)?;
+                    anns.push(((ann & 0xFFFFFF) >> 8, ann & 0xFF));
+                }
+                if let Some((size, _)) = anns.iter().find(|c| c.1 == ANN_DATA) {
+                    // for data, the "argument index" is actually its size in bytes
+                    let mut data = vec![0; *size as usize];
+                    f.read_exact(&mut data)?;
+                    let mut padding = vec![0; (4 - data.len() % 4) % 4];
+                    f.read_exact(&mut padding)?;
+                    let size = (data.len() + padding.len()) as u32;
+                    statements.push(Statement::Data { offset, data });
+                    offset += size;
+                    continue;
+                }
+                op_int = u32::read_from(f, ByteOrder::LittleEndian)?;
+            }
+            let arg_count = (op_int >> 10) & 0xF;
+            let mut args = vec![];
+            for _ in 0..arg_count {
+                args.push(u32::read_from(f, ByteOrder::LittleEndian)?);
+            }
+            statements.push(Statement::Command {
+                offset,
+                cmd: (op_int & 0x3FF) as u16,
+                arg0: op_int >> 14,
+                args,
+                anns,
+            });
+            offset += 4 * (1 + arg_count);
+        }
+        let mut strings = vec![];
+        f.read_to_end(&mut strings)?;
+
+        Ok(Self {
+            index,
+            start,
+            assets,
+            statements,
+            strings_offset: offset,
+            strings,
+        })
+    }
+
+    /// Finds the statement at a given position in the tickflow
+    pub fn position_of(&self, offset: u32) -> Option<usize> {
+        self.statements
+            .binary_search_by_key(&offset, Statement::offset)
+            .ok()
+    }
+
+    /// Reads the string that a string pointer argument points to
+    pub fn string_at(&self, offset: u32, is_unicode: bool) -> Option<String> {
+        Some(decode_string(
+            self.string_chars_at(offset, is_unicode)?,
+            is_unicode,
+        ))
+    }
+
+    /// Gets the encoded characters of the string that a string pointer argument points to,
+    /// without its terminator
+    pub fn string_chars_at(&self, offset: u32, is_unicode: bool) -> Option<&[u8]> {
+        let data = self
+            .strings
+            .get(offset.checked_sub(self.strings_offset)? as usize..)?;
+        Some(string_chars(data, is_unicode))
+    }
+}
+
+/// Gets the encoded characters of the string at the start of `data`, without its terminator
+fn string_chars(data: &[u8], is_unicode: bool) -> &[u8] {
+    let len = if is_unicode {
+        2 * data.chunks_exact(2).take_while(|c| *c != [0, 0]).count()
+    } else {
+        data.iter().take_while(|c| **c != 0).count()
+    };
+    &data[..len]
+}
+
+/// Turns the encoded characters of a string back into text
+pub fn decode_string(chars: &[u8], is_unicode: bool) -> String {
+    if is_unicode {
+        let chars = chars
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+        String::from_utf16_lossy(&chars)
+    } else {
+        String::from_utf8_lossy(chars).into_owned()
+    }
+}
+
+/// String data for compiled tickflow, where every distinct string is only stored once
+///
+/// ASCII and UTF-16 strings are kept apart, and each one is null-terminated and padded to a
+/// multiple of 4 bytes.
+#[derive(Debug, Clone, Default)]
+pub struct StringPool {
+    data: Vec<u8>,
+    offsets: HashMap<(bool, Vec<u8>), u32>,
+}
+
+impl StringPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a string if it's not in the pool yet, and gets its position in the pool
+    pub fn add(&mut self, value: &str, is_unicode: bool) -> u32 {
+        if is_unicode {
+            let chars = value
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>();
+            self.add_encoded(&chars, true)
+        } else {
+            self.add_encoded(value.as_bytes(), false)
+        }
+    }
+
+    /// Like [`Self::add`], with the string already encoded and without its terminator
+    pub fn add_encoded(&mut self, chars: &[u8], is_unicode: bool) -> u32 {
+        let key = (is_unicode, chars.to_vec());
+        if let Some(c) = self.offsets.get(&key) {
+            return *c;
+        }
+        let offset = self.data.len() as u32;
+        self.data.extend(chars);
+        let padding = match is_unicode {
+            true if chars.len() % 4 == 2 => 2,
+            true => 4,
+            false => 4 - chars.len() % 4,
+        };
+        self.data.extend(vec![0; padding]);
+        self.offsets.insert(key, offset);
+        offset
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Every string in the pool in the order they're stored, as their position, whether they're
+    /// UTF-16 and their encoded characters
+    pub fn strings(&self) -> impl Iterator<Item = (u32, bool, &[u8])> {
+        let mut out = self
+            .offsets
+            .iter()
+            .map(|((is_unicode, chars), offset)| (*offset, *is_unicode, chars.as_slice()))
+            .collect::<Vec<_>>();
+        out.sort_by_key(|c| c.0);
+        out.into_iter()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}